@@ -0,0 +1,294 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// query benchmarks over a synthetic history
+//
+// cargo bench --features count-allocs -- --nocapture also prints how many
+// allocations each query makes, with a fresh scratch and with one reused
+// between queries, and how much memory the index takes next to the map per
+// line it replaced. the feature switches tests to the system allocator, so
+// every allocation goes through malloc, and the malloc here counts them
+// before handing them to glibc.
+
+use std::collections::HashMap;
+#[cfg(feature = "count-allocs")]
+use std::sync::atomic::Ordering;
+
+use test::Bencher;
+
+use config::Config;
+use index::tests::HashLine;
+use search::{SearchBase, SearchMode, Scratch};
+
+const LINES: usize = 50000;
+const MATCHES: usize = 10;
+
+const COMMANDS: &'static [&'static str] = &[
+    "git", "cargo", "ls", "cd", "grep", "docker", "kubectl", "ssh", "make", "vim"
+];
+
+const WORDS: &'static [&'static str] = &[
+    "status", "commit", "build", "--release", "-la", "src/main.rs", "deploy", "--env=prod", "logs",
+    "origin/master", "Makefile", "README.md", "|", "&&", "tail", "-n", "100", "user@host",
+    "feature/search", "getUserName", "café", "test"
+];
+
+// counting replaces malloc and friends for the whole test binary, and
+// needs glibc's __libc_ functions, so it's only built when asked for
+#[cfg(feature = "count-allocs")]
+mod counting {
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use libc::{c_int, c_void, size_t, EINVAL, ENOMEM};
+
+    extern "C" {
+        fn __libc_malloc(size: size_t) -> *mut c_void;
+        fn __libc_calloc(count: size_t, size: size_t) -> *mut c_void;
+        fn __libc_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void;
+        fn __libc_memalign(align: size_t, size: size_t) -> *mut c_void;
+        fn __libc_valloc(size: size_t) -> *mut c_void;
+        fn __libc_pvalloc(size: size_t) -> *mut c_void;
+        fn __libc_free(ptr: *mut c_void);
+        fn malloc_usable_size(ptr: *mut c_void) -> size_t;
+    }
+
+    pub static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+    // bytes allocated and not freed, this wraps if something allocated before
+    // the counting started is freed, so only differences mean anything
+    pub static LIVE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    unsafe fn allocated(ptr: *mut c_void) -> *mut c_void {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        if !ptr.is_null() {
+            LIVE.fetch_add(malloc_usable_size(ptr) as usize, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+        allocated(__libc_malloc(size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+        allocated(__libc_calloc(count, size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+        let old = if ptr.is_null() {
+            0
+        } else {
+            malloc_usable_size(ptr)
+        };
+        let moved = __libc_realloc(ptr, size);
+        if !moved.is_null() || size == 0 {
+            // the old allocation is gone
+            LIVE.fetch_sub(old as usize, Ordering::Relaxed);
+        }
+        allocated(moved)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+        allocated(__libc_memalign(align, size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+        allocated(__libc_memalign(align, size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn posix_memalign(out: *mut *mut c_void, align: size_t, size: size_t) -> c_int {
+        let word = ::std::mem::size_of::<*mut c_void>() as size_t;
+        if align % word != 0 || !(align as usize).is_power_of_two() {
+            return EINVAL;
+        }
+
+        let ptr = allocated(__libc_memalign(align, size));
+        if ptr.is_null() && size != 0 {
+            return ENOMEM;
+        }
+        *out = ptr;
+        0
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void {
+        allocated(__libc_valloc(size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+        allocated(__libc_pvalloc(size))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn free(ptr: *mut c_void) {
+        if !ptr.is_null() {
+            LIVE.fetch_sub(malloc_usable_size(ptr) as usize, Ordering::Relaxed);
+        }
+        __libc_free(ptr)
+    }
+}
+
+// the allocations made and bytes live so far, if they're being counted
+#[cfg(feature = "count-allocs")]
+fn counters() -> Option<(usize, usize)> {
+    Some((counting::ALLOCATIONS.load(Ordering::Relaxed), counting::LIVE.load(Ordering::Relaxed)))
+}
+
+#[cfg(not(feature = "count-allocs"))]
+fn counters() -> Option<(usize, usize)> {
+    None
+}
+
+// the same history every time, from a linear congruential generator
+fn history(lines: usize) -> Vec<String> {
+    let mut seed: u32 = 1;
+    let mut next = || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as usize
+    };
+
+    (0..lines).map(|_| {
+        let mut line = COMMANDS[next() % COMMANDS.len()].to_owned();
+        for _ in 0..(next() % 6) {
+            line.push(' ');
+            line.push_str(WORDS[next() % WORDS.len()]);
+        }
+        line
+    }).collect()
+}
+
+fn synthetic(lines: usize) -> SearchBase {
+    let mut base = SearchBase::new(Config::default());
+    for line in history(lines).into_iter() {
+        base.add_line(line, None);
+    }
+    base.shrink_to_fit();
+    base
+}
+
+// the same history in a map of lines by their normalized form
+fn hashed(lines: usize) -> HashMap<String, HashLine> {
+    let config = Config::default();
+    let scorer = config.scorer.create();
+    let mut map: HashMap<String, HashLine> = HashMap::new();
+    for (number, line) in history(lines).into_iter().enumerate() {
+        let key = config.normalize.apply(&line);
+        let respelled = match map.get_mut(&key) {
+            Some(info) => {
+                info.occurrences.count += 1;
+                info.occurrences.last = number as isize;
+                info.factor = number as isize;
+                info.text != line
+            },
+            None => {
+                map.insert(key, HashLine::new(line, number as isize, &*scorer, &config));
+                continue;
+            }
+        };
+        if respelled {
+            let occurrences = map[&key].occurrences;
+            let mut info = HashLine::new(line, number as isize, &*scorer, &config);
+            info.occurrences = occurrences;
+            map.insert(key, info);
+        }
+    }
+    map
+}
+
+// what build returns and the bytes it left allocated
+fn live<T, F: FnOnce() -> T>(build: F) -> (T, Option<usize>) {
+    let before = counters();
+    let built = build();
+    let after = counters();
+    (built, before.and_then(|(_, before)| {after.map(|(_, after)| {after.wrapping_sub(before)})}))
+}
+
+fn allocations(base: &SearchBase, query: &str, scratch: &mut Scratch) -> Option<usize> {
+    let before = counters();
+    base.query(query, SearchMode::Fuzzy, false, MATCHES, 0, scratch);
+    let after = counters();
+    before.and_then(|(before, _)| {after.map(|(after, _)| {after - before})})
+}
+
+fn bench_query(b: &mut Bencher, query: &str, reuse: bool) {
+    let base = synthetic(LINES);
+    let mut scratch = Scratch::default();
+
+    // the first query grows the scratch to size
+    let fresh = allocations(&base, query, &mut scratch);
+    let reused = allocations(&base, query, &mut scratch);
+    match (fresh, reused) {
+        (Some(fresh), Some(reused)) => {
+            println!("{:?}: {} allocations with a fresh scratch, {} reusing one", query, fresh, reused);
+        },
+        _ => {}
+    }
+
+    b.iter(|| {
+        if reuse {
+            base.query(query, SearchMode::Fuzzy, false, MATCHES, 0, &mut scratch)
+        } else {
+            base.query(query, SearchMode::Fuzzy, false, MATCHES, 0, &mut Scratch::default())
+        }
+    });
+}
+
+#[bench]
+fn fuzzy(b: &mut Bencher) {
+    bench_query(b, "gco", true);
+}
+
+#[bench]
+fn fuzzy_fresh_scratch(b: &mut Bencher) {
+    bench_query(b, "gco", false);
+}
+
+#[bench]
+fn terms(b: &mut Bencher) {
+    bench_query(b, "git orig", true);
+}
+
+#[bench]
+fn case_sensitive(b: &mut Bencher) {
+    bench_query(b, "gUN", true);
+}
+
+#[bench]
+fn exact(b: &mut Bencher) {
+    bench_query(b, "'deploy prod", true);
+}
+
+#[bench]
+fn index(b: &mut Bencher) {
+    let (base, columns) = live(|| {synthetic(LINES)});
+    let (map, per_line) = live(|| {hashed(LINES)});
+    match (columns, per_line) {
+        (Some(columns), Some(per_line)) => {
+            println!("{} lines: {} bytes in columns, {} in a map per line", LINES, columns, per_line);
+        },
+        _ => {
+            println!("{} lines: {} bytes in columns", LINES, base.memory());
+        }
+    }
+    drop(base);
+    drop(map);
+
+    b.iter(|| {synthetic(LINES / 50)});
+}
@@ -16,7 +16,6 @@
 #include <string.h>
 #include <sys/ioctl.h>
 #include <signal.h>
-#include <errno.h>
 
 struct bis_error_info_t {
   char *error_str;
@@ -57,8 +56,24 @@ int bis_prepare_terminal() {
   // disable echo
   terminfo_p.c_lflag &= ~ECHO;
 
-  // set terminal options
-  if (tcsetattr(STDOUT_FILENO, TCSAFLUSH, &terminfo_p) != 0) {
+  // deliver C-c, C-z and C-\ as input instead of signals
+  terminfo_p.c_lflag &= ~ISIG;
+
+  // deliver C-v and C-o as input
+  terminfo_p.c_lflag &= ~IEXTEN;
+
+  // deliver C-s and C-q as input instead of flow control
+  terminfo_p.c_iflag &= ~IXON;
+
+  // don't translate carriage returns, and don't mangle input
+  terminfo_p.c_iflag &= ~(ICRNL | INLCR | IGNCR | ISTRIP | BRKINT);
+
+  // block until at least one byte is available, with no timeout
+  terminfo_p.c_cc[VMIN] = 1;
+  terminfo_p.c_cc[VTIME] = 0;
+
+  // set terminal options, keeping any input that is already queued
+  if (tcsetattr(STDOUT_FILENO, TCSANOW, &terminfo_p) != 0) {
     bis_error_info.error_str = "Error setting terminal attributes";
     bis_error_info.is_errno = 1;
     return -1;
@@ -86,6 +101,41 @@ int bis_restore_terminal() {
   return 0;
 }
 
+int bis_read_pending(char *buffer, size_t length, size_t *read_length) {
+  int pending;
+  ssize_t result;
+
+  *read_length = 0;
+
+  // find out how much input is waiting
+  if (ioctl(STDIN_FILENO, FIONREAD, &pending) != 0) {
+    bis_error_info.error_str = "ioctl call failed";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  if (pending <= 0) {
+    // nothing to read
+    return 0;
+  }
+
+  if ((size_t) pending < length) {
+    length = (size_t) pending;
+  }
+
+  // read only what is queued so we never block
+  if ((result = read(STDIN_FILENO, buffer, length)) < 0) {
+    bis_error_info.error_str = "Failed to read pending input";
+    bis_error_info.is_errno = 1;
+    return -1;
+  }
+
+  *read_length = (size_t) result;
+
+  // return success
+  return 0;
+}
+
 int bis_get_terminal_size(struct bis_term_size_t *size) {
   struct winsize term_size;
   // request the terminal size
@@ -103,50 +153,18 @@ int bis_get_terminal_size(struct bis_term_size_t *size) {
   return 0;
 }
 
-int bis_mask_sigint() {
-  sigset_t set;
-
-  sigemptyset(&set);
-  sigaddset(&set, SIGINT);
-  if (sigprocmask(SIG_BLOCK, &set, NULL) != 0) {
-    bis_error_info.error_str = "sigprocmask failed";
+int bis_suspend() {
+  // stop ourselves the way the terminal driver would have
+  if (raise(SIGTSTP) != 0) {
+    bis_error_info.error_str = "Failed to raise SIGTSTP";
     bis_error_info.is_errno = 1;
     return -1;
   }
 
-  // return success
+  // we only get here after SIGCONT
   return 0;
 }
 
-int bis_wait_sigint() {
-  sigset_t set;
-
-  sigemptyset(&set);
-  sigaddset(&set, SIGINT);
-
-  int result;
-
-  for (;;) {
-    if ((result = sigwaitinfo(&set, NULL)) == -1) {
-      if (errno != EINTR) {
-        bis_error_info.error_str = "sigwaitinfo failed";
-        bis_error_info.is_errno = 1;
-        return -1;
-      }
-
-      // otherwise try again
-    } else if (result != SIGINT) {
-      // we caught some other signal
-      bis_error_info.error_str = "Caught signal other than SIGINT";
-      bis_error_info.is_errno = 0;
-      return -1;
-    } else {
-      // we caught the signal we wanted
-      return 0;
-    }
-  }
-}
-
 int bis_insert_input(const char *input) {
   // insert the input string into the input queue
   for (; *input != 0; input++) {
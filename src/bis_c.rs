@@ -15,8 +15,10 @@
 // bindings into bis_c.c
 
 use std::ffi::CString;
+use std::cmp;
 
-use error::StringError;
+use error::{BisError, ErrorKind};
+use constants::*;
 
 // this object exists to track Rust's memory model
 // that way the terminal is restored when the main
@@ -36,7 +38,7 @@ mod c {
     use std::ffi;
     use std::io;
 
-    use error::StringError;
+    use error::{BisError, ErrorKind};
 
     #[repr(C)]
     struct bis_error_info_t {
@@ -55,15 +57,15 @@ mod c {
         
         pub fn bis_prepare_terminal() -> c_int;
         pub fn bis_restore_terminal() -> c_int;
+        pub fn bis_read_pending(buffer: *mut c_char, length: size_t, read_length: *mut size_t) -> c_int;
         pub fn bis_get_terminal_size(size: *mut bis_term_size_t) -> c_int;
-        pub fn bis_mask_sigint() -> c_int;
-        pub fn bis_wait_sigint() -> c_int;
+        pub fn bis_suspend() -> c_int;
         pub fn bis_insert_input(input: *const c_char) -> c_int;
     }
 
-    pub unsafe fn get_bis_error() -> StringError {
+    pub unsafe fn get_bis_error(kind: ErrorKind) -> BisError {
         let error_cstr = ffi::CStr::from_ptr(bis_error_info.error_str);
-        StringError::new(error_cstr.to_string_lossy().into_owned(),
+        BisError::new(kind, error_cstr.to_string_lossy().into_owned(),
                          match bis_error_info.is_errno {
                              1 => Some(Box::new(io::Error::last_os_error())),
                              _ => None
@@ -91,23 +93,19 @@ impl Drop for TermTrack {
 }
 
 impl TermTrack {
-    pub fn prepare(&mut self) -> Result<(), StringError> {
+    pub fn prepare(&mut self) -> Result<(), BisError> {
         debug!("Preparing terminal");
         match unsafe {c::bis_prepare_terminal()} {
             0 => Ok(()),
-            _ => Err(unsafe {c::get_bis_error()})
+            _ => Err(unsafe {c::get_bis_error(ErrorKind::Terminal)})
         }
     }
 
-    pub fn restore(&mut self) -> Result<(), StringError> {
-        debug!("Restoring terminal");
-        match unsafe {c::bis_restore_terminal()} {
-            0 => Ok(()),
-            _ => Err(unsafe {c::get_bis_error()})
-        }
+    pub fn restore(&mut self) -> Result<(), BisError> {
+        restore_terminal()
     }
 
-    pub fn get_size(&self) -> Result<TermSize, StringError> {
+    pub fn get_size(&self) -> Result<TermSize, BisError> {
         debug!("Getting terminal size");
         let mut term_size = c::bis_term_size_t {
             rows: 0,
@@ -119,38 +117,118 @@ impl TermTrack {
                 rows: term_size.rows as usize,
                 cols: term_size.cols as usize
             }),
-            _ => Err(unsafe {c::get_bis_error()})
+            _ => Err(unsafe {c::get_bis_error(ErrorKind::Terminal)})
         }
     }
 }
 
-pub fn mask_sigint() -> Result<(), StringError> {
-    debug!("Masking sigint");
-    match unsafe {c::bis_mask_sigint()} {
+// usable without a TermTrack, e.g. from a panic hook
+pub fn restore_terminal() -> Result<(), BisError> {
+    debug!("Restoring terminal");
+    match unsafe {c::bis_restore_terminal()} {
         0 => Ok(()),
-        _ => Err(unsafe {c::get_bis_error()})
+        _ => Err(unsafe {c::get_bis_error(ErrorKind::Terminal)})
+    }
+}
+
+// everything typed before we started. it's read PENDING_LEN bytes at a
+// time, and a character split between two reads is finished by the next
+pub fn read_pending() -> Result<String, BisError> {
+    debug!("Reading pending input");
+    let mut buffer = vec![0u8; PENDING_LEN];
+    let mut partial: Vec<u8> = vec![];
+    let mut pending = String::new();
+
+    loop {
+        let mut read_length = 0;
+        match unsafe {c::bis_read_pending(buffer.as_mut_ptr() as *mut ::libc::c_char,
+                                          buffer.len() as ::libc::size_t, &mut read_length)} {
+            0 => {
+                trace!("Read {} bytes of pending input", read_length);
+            },
+            _ => return Err(unsafe {c::get_bis_error(ErrorKind::Terminal)})
+        }
+
+        if read_length == 0 {
+            break;
+        }
+
+        partial.extend(buffer[..read_length as usize].iter().cloned());
+        let complete = complete_utf8(&partial);
+        pending.push_str(&String::from_utf8_lossy(&partial[..complete]));
+        partial = partial[complete..].to_vec();
+    }
+
+    if !partial.is_empty() {
+        debug!("Pending input ends in the middle of a character");
+        pending.push_str(&String::from_utf8_lossy(&partial));
     }
+
+    Ok(pending)
 }
 
-pub fn wait_sigint() -> Result<(), StringError> {
-    debug!("Waiting for sigint");
-    match unsafe {c::bis_wait_sigint()} {
+// how much of bytes is left without an unfinished utf-8 character at the end
+fn complete_utf8(bytes: &[u8]) -> usize {
+    // a character is at most four bytes, look back for where the last starts
+    for back in 1..cmp::min(bytes.len(), 4) + 1 {
+        let b = bytes[bytes.len() - back];
+        if b & 0xc0 != 0x80 {
+            let length = if b >= 0xf0 {
+                4
+            } else if b >= 0xe0 {
+                3
+            } else if b >= 0xc0 {
+                2
+            } else {
+                1
+            };
+            if length > back {
+                return bytes.len() - back;
+            } else {
+                return bytes.len();
+            }
+        }
+    }
+
+    bytes.len()
+}
+
+pub fn suspend() -> Result<(), BisError> {
+    debug!("Suspending");
+    match unsafe {c::bis_suspend()} {
         0 => Ok(()),
-        _ => Err(unsafe {c::get_bis_error()})
+        _ => Err(unsafe {c::get_bis_error(ErrorKind::Terminal)})
     }
 }
 
-pub fn insert_input<T: Into<Vec<u8>>>(input: T) -> Result<(), StringError> {
+pub fn insert_input<T: Into<Vec<u8>>>(input: T) -> Result<(), BisError> {
     let cstr = match CString::new(input) {
         Ok(s) => s,
-        Err(e) => return Err(StringError::new("Failed to create CString", Some(Box::new(e))))
+        Err(e) => return Err(BisError::new(ErrorKind::Insert, "Failed to create CString", Some(Box::new(e))))
     };
 
     match unsafe {c::bis_insert_input(cstr.as_ptr())} {
         0 => {},
-        _ => return Err(unsafe {c::get_bis_error()})
+        _ => return Err(unsafe {c::get_bis_error(ErrorKind::Insert)})
     }
 
     // return success
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::complete_utf8;
+
+    #[test]
+    fn complete_utf8_keeps_split_characters() {
+        assert_eq!(complete_utf8(b"ls"), 2);
+        assert_eq!(complete_utf8(b""), 0);
+        // "é" is c3 a9, "€" is e2 82 ac
+        assert_eq!(complete_utf8(b"caf\xc3\xa9"), 5);
+        assert_eq!(complete_utf8(b"caf\xc3"), 3);
+        assert_eq!(complete_utf8(b"\xe2\x82"), 0);
+        assert_eq!(complete_utf8(b"a\xe2\x82\xac"), 4);
+        assert_eq!(complete_utf8(b"a\xf0\x9f\x8e"), 1);
+    }
+}
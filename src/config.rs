@@ -0,0 +1,598 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// runtime configuration
+//
+// settings are applied in order, each overriding the last:
+// the defaults in constants.rs, the config file, BIS_* environment
+// variables, and finally the command line
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use std::env;
+
+use error::{BisError, ErrorKind};
+use constants::*;
+use fold::CaseMode;
+use scorer::ScorerKind;
+use normalize::Normalize;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub help: bool,
+    pub history: Vec<PathBuf>,
+    pub selections: Option<PathBuf>,
+    pub matches: usize,
+    pub offset: usize,
+    pub prompt: String,
+    pub regex: bool,
+    pub explain: bool,
+    pub query: Option<String>,
+    pub case: CaseMode,
+    pub scorer: ScorerKind,
+    pub normalize: Normalize,
+    pub whitespace_factor: isize,
+    pub whitespace_reduce: isize,
+    pub class_factor: isize,
+    pub first_factor: isize,
+    pub class_reduce: isize,
+    pub word_factor: isize,
+    pub command_factor: isize,
+    pub path_factor: isize,
+    pub flag_factor: isize,
+    pub assign_factor: isize,
+    pub dist_weight: isize,
+    pub heat_weight: isize,
+    pub factor_reduce: isize,
+    pub count_weight: isize,
+    pub frecency_weight: isize,
+    pub prefix_weight: isize,
+    pub frecency_half_life: u64,
+    pub run_weight: isize,
+    pub lead_weight: isize,
+    pub length_reduce: isize,
+    pub typo_every: usize,
+    pub typo_penalty: isize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Single(String),
+    List(Vec<String>)
+}
+
+// keys that are true when given on the command line without a value
+const FLAGS: &'static [&'static str] = &["regex", "explain"];
+
+// keys that only make sense for one run, so only the command line sets them
+const CLI_ONLY: &'static [&'static str] = &["query", "offset"];
+
+// every key that can be set, in the order --help lists them
+const KEYS: &'static [(&'static str, &'static str)] = &[
+    ("history", "history file to search, may be given more than once"),
+    ("selections", "where to log picked matches, instead of ~/.local/share/bis/selections"),
+    ("matches", "number of matches to show"),
+    ("offset", "number of better matches to skip, with --query"),
+    ("prompt", "text shown before the query"),
+    ("regex", "start in regular expression mode"),
+    ("explain", "show why each match scored what it did"),
+    ("query", "print the matches for this query instead of starting the UI"),
+    ("case", "smart, ignore or respect case"),
+    ("scorer", "ranking algorithm: bis, flx or fzf"),
+    ("normalize", "what makes two commands the same: trim, whitespace, semicolon, quotes or none"),
+    ("whitespace_factor", "heat after whitespace"),
+    ("whitespace_reduce", "how quickly whitespace heat falls off"),
+    ("class_factor", "heat after a character class change"),
+    ("first_factor", "heat of the first character"),
+    ("class_reduce", "how quickly class change heat falls off"),
+    ("word_factor", "heat at the start of a camelCase or snake_case word"),
+    ("command_factor", "heat of the first character after |, &&, ; or $("),
+    ("path_factor", "heat of the first character after /"),
+    ("flag_factor", "heat of the first character of a -flag or --flag"),
+    ("assign_factor", "heat of the first character after ="),
+    ("dist_weight", "weight of the distance between matched characters"),
+    ("heat_weight", "weight of the heat of matched characters"),
+    ("factor_reduce", "how much recency is divided down"),
+    ("count_weight", "bonus each time the number of runs of a line doubles"),
+    ("frecency_weight", "bonus for each time a line was picked"),
+    ("prefix_weight", "extra bonus for each time a line was picked with a query that started with this one"),
+    ("frecency_half_life", "days until a pick counts half as much"),
+    ("run_weight", "weight of each pair of matched characters next to each other"),
+    ("lead_weight", "weight of each character before the first match"),
+    ("length_reduce", "longer lines lose a point per this many characters"),
+    ("typo_every", "allow one typo per this many query characters when nothing matches, 0 to disable"),
+    ("typo_penalty", "score lost for each typo")
+];
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            help: false,
+            history: vec![],
+            selections: None,
+            matches: MATCH_NUMBER,
+            offset: 0,
+            prompt: PROMPT.to_owned(),
+            regex: false,
+            explain: false,
+            query: None,
+            case: CaseMode::Smart,
+            scorer: ScorerKind::Bis,
+            normalize: Normalize::default(),
+            whitespace_factor: WHITESPACE_FACTOR,
+            whitespace_reduce: WHITESPACE_REDUCE,
+            class_factor: CLASS_FACTOR,
+            first_factor: FIRST_FACTOR,
+            class_reduce: CLASS_REDUCE,
+            word_factor: WORD_FACTOR,
+            command_factor: COMMAND_FACTOR,
+            path_factor: PATH_FACTOR,
+            flag_factor: FLAG_FACTOR,
+            assign_factor: ASSIGN_FACTOR,
+            dist_weight: DIST_WEIGHT,
+            heat_weight: HEAT_WEIGHT,
+            factor_reduce: FACTOR_REDUCE,
+            count_weight: COUNT_WEIGHT,
+            frecency_weight: FRECENCY_WEIGHT,
+            prefix_weight: PREFIX_WEIGHT,
+            frecency_half_life: FRECENCY_HALF_LIFE,
+            run_weight: RUN_WEIGHT,
+            lead_weight: LEAD_WEIGHT,
+            length_reduce: LENGTH_REDUCE,
+            typo_every: TYPO_EVERY,
+            typo_penalty: TYPO_PENALTY
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(key: &str, value: Value) -> Result<T, BisError>
+    where T::Err: ::std::error::Error + Send + 'static {
+    match value {
+        Value::Single(s) => match s.trim().parse() {
+            Ok(n) => Ok(n),
+            Err(e) => Err(BisError::new(ErrorKind::Parse, format!("Invalid number for {}", key), Some(Box::new(e))))
+        },
+        Value::List(_) => Err(BisError::new(ErrorKind::Parse, format!("{} takes a single number", key), None))
+    }
+}
+
+fn parse_string(key: &str, value: Value) -> Result<String, BisError> {
+    match value {
+        Value::Single(s) => Ok(s),
+        Value::List(_) => Err(BisError::new(ErrorKind::Parse, format!("{} takes a single string", key), None))
+    }
+}
+
+fn parse_bool(key: &str, value: Value) -> Result<bool, BisError> {
+    match value {
+        Value::Single(ref s) if s == "true" => Ok(true),
+        Value::Single(ref s) if s == "false" => Ok(false),
+        _ => Err(BisError::new(ErrorKind::Parse, format!("{} takes true or false", key), None))
+    }
+}
+
+// config files can't rely on the shell to expand ~
+fn expand_home(path: String) -> PathBuf {
+    if path.starts_with("~/") {
+        match env::home_dir() {
+            Some(home) => return home.join(&path[2..]),
+            None => {
+                debug!("No home directory to expand {:?}", path);
+            }
+        }
+    }
+
+    PathBuf::from(path)
+}
+
+// parse a quoted string from the config file, returning it and the rest of the line
+fn parse_quoted(input: &str) -> Result<(String, &str), BisError> {
+    let mut chars = input.char_indices();
+    let quote = match chars.next() {
+        Some((_, c)) => c,
+        None => return Err(BisError::new(ErrorKind::Parse, "Expected a string", None))
+    };
+
+    let mut result = String::new();
+    let mut escaped = false;
+
+    for (idx, c) in chars {
+        if escaped {
+            result.push(match c {
+                'n' => '\n',
+                't' => '\t',
+                other => other
+            });
+            escaped = false;
+        } else if c == '\\' && quote == '"' {
+            // only basic strings have escapes
+            escaped = true;
+        } else if c == quote {
+            return Ok((result, &input[idx + c.len_utf8()..]));
+        } else {
+            result.push(c);
+        }
+    }
+
+    Err(BisError::new(ErrorKind::Parse, "Unterminated string", None))
+}
+
+// parse the value half of a "key = value" line
+fn parse_value(input: &str) -> Result<Value, BisError> {
+    let input = input.trim();
+
+    if input.starts_with('"') || input.starts_with('\'') {
+        let (s, rest) = try!(parse_quoted(input));
+        if !rest.trim().is_empty() && !rest.trim().starts_with('#') {
+            return Err(BisError::new(ErrorKind::Parse, format!("Unexpected text after string: {}", rest.trim()), None));
+        }
+        Ok(Value::Single(s))
+    } else if input.starts_with('[') {
+        let mut items = vec![];
+        let mut rest = input[1..].trim_left();
+
+        loop {
+            if rest.starts_with(']') {
+                break;
+            }
+
+            let (item, after) = try!(parse_quoted(rest));
+            items.push(item);
+
+            rest = after.trim_left();
+            if rest.starts_with(',') {
+                rest = rest[1..].trim_left();
+            } else if !rest.starts_with(']') {
+                return Err(BisError::new(ErrorKind::Parse, "Expected , or ] in list", None));
+            }
+        }
+
+        Ok(Value::List(items))
+    } else {
+        // numbers and booleans, possibly followed by a comment
+        let bare = match input.find('#') {
+            Some(idx) => input[..idx].trim(),
+            None => input
+        };
+        if bare.is_empty() {
+            Err(BisError::new(ErrorKind::Parse, "Missing value", None))
+        } else {
+            Ok(Value::Single(bare.to_owned()))
+        }
+    }
+}
+
+impl Config {
+    pub fn usage() -> String {
+        let mut result = format!("Usage: bis [OPTIONS]\n\nOptions:\n");
+        result.push_str("  --config PATH\n        config file to read instead of ~/.config/bis/config.toml\n");
+        result.push_str("  --help\n        show this message\n");
+        for &(key, description) in KEYS.iter() {
+            if FLAGS.contains(&key) {
+                result.push_str(&format!("  --{}\n        {}\n", key.replace("_", "-"), description));
+            } else {
+                result.push_str(&format!("  --{} VALUE\n        {}\n", key.replace("_", "-"), description));
+            }
+        }
+        result.push_str(&format!("\nEvery option except --{} can also be set in the config file as key = value, \
+                                  or in the environment as BIS_KEY.\n", CLI_ONLY.join(" and --")));
+        result
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), BisError> {
+        trace!("Setting {} to {:?}", key, value);
+        match key {
+            "history" => {
+                self.history = match value {
+                    Value::Single(s) => vec![expand_home(s)],
+                    Value::List(l) => l.into_iter().map(expand_home).collect()
+                };
+            },
+            "selections" => self.selections = Some(expand_home(try!(parse_string(key, value)))),
+            "matches" => self.matches = try!(parse_number(key, value)),
+            "offset" => self.offset = try!(parse_number(key, value)),
+            "prompt" => self.prompt = try!(parse_string(key, value)),
+            "regex" => self.regex = try!(parse_bool(key, value)),
+            "explain" => self.explain = try!(parse_bool(key, value)),
+            "query" => self.query = Some(try!(parse_string(key, value))),
+            "case" => {
+                let name = try!(parse_string(key, value));
+                self.case = match CaseMode::from_name(&name) {
+                    Some(mode) => mode,
+                    None => return Err(BisError::new(ErrorKind::Parse,
+                                                     format!("case must be smart, ignore or respect, not {}", name), None))
+                };
+            },
+            "scorer" => {
+                let name = try!(parse_string(key, value));
+                self.scorer = match ScorerKind::from_name(&name) {
+                    Some(kind) => kind,
+                    None => return Err(BisError::new(ErrorKind::Parse,
+                                                     format!("scorer must be bis, flx or fzf, not {}", name), None))
+                };
+            },
+            "normalize" => {
+                // a list in the config file, comma separated anywhere else
+                let names = match value {
+                    Value::Single(s) => s.split(',').map(|n| {n.to_owned()}).collect(),
+                    Value::List(l) => l
+                };
+                self.normalize = match Normalize::from_names(&names) {
+                    Some(normalize) => normalize,
+                    None => return Err(BisError::new(ErrorKind::Parse,
+                                                     format!("normalize takes trim, whitespace, semicolon, quotes or none, \
+                                                              not {}", names.join(",")), None))
+                };
+            },
+            "whitespace_factor" => self.whitespace_factor = try!(parse_number(key, value)),
+            "whitespace_reduce" => self.whitespace_reduce = try!(parse_number(key, value)),
+            "class_factor" => self.class_factor = try!(parse_number(key, value)),
+            "first_factor" => self.first_factor = try!(parse_number(key, value)),
+            "class_reduce" => self.class_reduce = try!(parse_number(key, value)),
+            "word_factor" => self.word_factor = try!(parse_number(key, value)),
+            "command_factor" => self.command_factor = try!(parse_number(key, value)),
+            "path_factor" => self.path_factor = try!(parse_number(key, value)),
+            "flag_factor" => self.flag_factor = try!(parse_number(key, value)),
+            "assign_factor" => self.assign_factor = try!(parse_number(key, value)),
+            "dist_weight" => self.dist_weight = try!(parse_number(key, value)),
+            "heat_weight" => self.heat_weight = try!(parse_number(key, value)),
+            "factor_reduce" => self.factor_reduce = try!(parse_number(key, value)),
+            "count_weight" => self.count_weight = try!(parse_number(key, value)),
+            "frecency_weight" => self.frecency_weight = try!(parse_number(key, value)),
+            "prefix_weight" => self.prefix_weight = try!(parse_number(key, value)),
+            "frecency_half_life" => self.frecency_half_life = try!(parse_number(key, value)),
+            "run_weight" => self.run_weight = try!(parse_number(key, value)),
+            "lead_weight" => self.lead_weight = try!(parse_number(key, value)),
+            "length_reduce" => self.length_reduce = try!(parse_number(key, value)),
+            "typo_every" => self.typo_every = try!(parse_number(key, value)),
+            "typo_penalty" => self.typo_penalty = try!(parse_number(key, value)),
+            _ => return Err(BisError::new(ErrorKind::Parse, format!("Unknown setting: {}", key), None))
+        }
+
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), BisError> {
+        // these are divisors
+        for &(key, value) in [("whitespace_reduce", self.whitespace_reduce),
+                              ("class_reduce", self.class_reduce),
+                              ("factor_reduce", self.factor_reduce),
+                              ("length_reduce", self.length_reduce)].iter() {
+            if value == 0 {
+                return Err(BisError::new(ErrorKind::Parse, format!("{} must not be zero", key), None));
+            }
+        }
+
+        if self.frecency_half_life == 0 {
+            return Err(BisError::new(ErrorKind::Parse, "frecency_half_life must be at least one day", None));
+        }
+
+        if self.matches == 0 {
+            return Err(BisError::new(ErrorKind::Parse, "matches must be at least one", None));
+        }
+
+        // the search keeps offset + matches of them
+        if self.offset.checked_add(self.matches).is_none() {
+            return Err(BisError::new(ErrorKind::Parse, "offset and matches are too large together", None));
+        }
+
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &PathBuf, required: bool) -> Result<(), BisError> {
+        let mut content = String::new();
+        match File::open(path).and_then(|mut f| {f.read_to_string(&mut content)}) {
+            Ok(_) => {
+                debug!("Read config file {:?}", path);
+            },
+            Err(e) => {
+                if required {
+                    return Err(BisError::new(ErrorKind::Parse,
+                                             format!("Could not read config file {}", path.display()),
+                                             Some(Box::new(e))));
+                } else {
+                    debug!("Not reading config file {:?}: {}", path, e);
+                    return Ok(());
+                }
+            }
+        }
+
+        for (number, raw) in content.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let context = format!("Error in {} on line {}", path.display(), number + 1);
+
+            let idx = match line.find('=') {
+                Some(idx) => idx,
+                None => return Err(BisError::wrap(context, BisError::new(ErrorKind::Parse,
+                                                                         "Expected key = value", None)))
+            };
+
+            let key = line[..idx].trim();
+            if CLI_ONLY.contains(&key) {
+                return Err(BisError::wrap(context, BisError::new(ErrorKind::Parse,
+                                                                 format!("{} can only be given on the command line", key),
+                                                                 None)));
+            }
+            match parse_value(&line[idx + 1..]).and_then(|value| {self.set(key, value)}) {
+                Ok(_) => {},
+                Err(e) => return Err(BisError::wrap(context, e))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_env(&mut self) -> Result<(), BisError> {
+        for &(key, _) in KEYS.iter().filter(|&&(key, _)| {!CLI_ONLY.contains(&key)}) {
+            let name = format!("BIS_{}", key.to_uppercase());
+            match env::var(&name) {
+                Ok(value) => {
+                    debug!("Got {} from the environment", name);
+                    let parsed = if key == "history" {
+                        // a path list, like PATH
+                        Value::List(value.split(':').filter(|p| {!p.is_empty()}).map(|p| {p.to_owned()}).collect())
+                    } else {
+                        Value::Single(value)
+                    };
+                    match self.set(key, parsed) {
+                        Ok(_) => {},
+                        Err(e) => return Err(BisError::wrap(format!("Error in {}", name), e))
+                    }
+                },
+                Err(_) => {
+                    trace!("{} is not set", name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn default_path() -> Option<PathBuf> {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => env::home_dir().map(|home| {home.join(".config")})
+        }.map(|dir| {dir.join("bis").join("config.toml")})
+    }
+
+    pub fn load() -> Result<Config, BisError> {
+        let mut config = Config::default();
+
+        // read the command line first so we know which config file to use
+        let mut config_path = None;
+        let mut overrides = vec![];
+        let mut history = vec![];
+        let mut args = env::args().skip(1);
+
+        loop {
+            let arg = match args.next() {
+                Some(arg) => arg,
+                None => break
+            };
+
+            if arg == "--help" || arg == "-h" {
+                // only on the command line, never from the config file
+                config.help = true;
+                continue;
+            }
+
+            if !arg.starts_with("--") {
+                return Err(BisError::new(ErrorKind::Parse, format!("Unexpected argument: {}", arg), None));
+            }
+
+            // accept both --key value and --key=value
+            let (name, inline) = match arg.find('=') {
+                Some(idx) => (arg[2..idx].to_owned(), Some(arg[idx + 1..].to_owned())),
+                None => (arg[2..].to_owned(), None)
+            };
+            let key = name.replace("-", "_");
+
+            if key != "config" && !KEYS.iter().any(|&(k, _)| {k == key}) {
+                return Err(BisError::new(ErrorKind::Parse, format!("Unknown option: --{}", name), None));
+            }
+
+            let value = match inline {
+                Some(v) => v,
+                None if FLAGS.contains(&key.as_str()) => "true".to_owned(),
+                None => match args.next() {
+                    Some(v) => v,
+                    None => return Err(BisError::new(ErrorKind::Parse, format!("--{} needs a value", name), None))
+                }
+            };
+
+            if key == "config" {
+                config_path = Some(PathBuf::from(value));
+            } else if key == "history" {
+                history.push(value);
+            } else {
+                overrides.push((key, Value::Single(value)));
+            }
+        }
+
+        if !history.is_empty() {
+            overrides.push(("history".to_owned(), Value::List(history)));
+        }
+
+        match config_path {
+            Some(ref path) => try!(config.read_file(path, true)),
+            None => match Config::default_path() {
+                Some(ref path) => try!(config.read_file(path, false)),
+                None => {
+                    debug!("No default config file location");
+                }
+            }
+        }
+
+        try!(config.read_env());
+
+        for (key, value) in overrides.into_iter() {
+            match config.set(&key, value) {
+                Ok(_) => {},
+                Err(e) => return Err(BisError::wrap(format!("Error in --{}", key.replace("_", "-")), e))
+            }
+        }
+
+        try!(config.check());
+
+        trace!("Loaded config: {:?}", config);
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Value, parse_value};
+
+    fn single(s: &str) -> Value {
+        Value::Single(s.to_owned())
+    }
+
+    #[test]
+    fn parse_strings() {
+        assert_eq!(parse_value(" \"Match: \" ").ok(), Some(single("Match: ")));
+        assert_eq!(parse_value("\"a\\tb\\n\\\"c\\\"\"").ok(), Some(single("a\tb\n\"c\"")));
+        // literal strings have no escapes
+        assert_eq!(parse_value("'C:\\bis' # windows").ok(), Some(single("C:\\bis")));
+        assert_eq!(parse_value("\"a # b\"").ok(), Some(single("a # b")));
+        assert!(parse_value("\"unterminated").is_err());
+        assert!(parse_value("\"a\" b").is_err());
+    }
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(parse_value("[\"~/.bash_history\", '~/.zsh_history']").ok(),
+                   Some(Value::List(vec!["~/.bash_history".to_owned(), "~/.zsh_history".to_owned()])));
+        assert_eq!(parse_value("[ \"a\" , ]").ok(), Some(Value::List(vec!["a".to_owned()])));
+        assert_eq!(parse_value("[]").ok(), Some(Value::List(vec![])));
+        assert!(parse_value("[\"a\" \"b\"]").is_err());
+        assert!(parse_value("[a]").is_err());
+        assert!(parse_value("[\"a\"").is_err());
+    }
+
+    #[test]
+    fn parse_bare_values() {
+        assert_eq!(parse_value("15").ok(), Some(single("15")));
+        assert_eq!(parse_value("true # start in regex mode").ok(), Some(single("true")));
+        assert!(parse_value("").is_err());
+        assert!(parse_value("# nothing").is_err());
+    }
+}
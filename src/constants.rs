@@ -18,16 +18,50 @@ pub const WHITESPACE_REDUCE: isize = 2;
 pub const CLASS_FACTOR: isize = 3;
 pub const FIRST_FACTOR: isize = 3;
 pub const CLASS_REDUCE: isize = 2;
+// camelCase and snake_case words
+pub const WORD_FACTOR: isize = 3;
+
+// shell syntax boundaries, on top of the above
+pub const COMMAND_FACTOR: isize = 4;
+pub const PATH_FACTOR: isize = 2;
+pub const FLAG_FACTOR: isize = 3;
+pub const ASSIGN_FACTOR: isize = 2;
 
 pub const DIST_WEIGHT: isize = -10;
 pub const HEAT_WEIGHT: isize = 5;
 pub const FACTOR_REDUCE: isize = 50;
+// per doubling of how often a line was run
+pub const COUNT_WEIGHT: isize = 5;
+// per pick from the selection log, and again if picked with a similar query
+pub const FRECENCY_WEIGHT: isize = 20;
+pub const PREFIX_WEIGHT: isize = 40;
+// picks count half as much after this many days
+pub const FRECENCY_HALF_LIFE: u64 = 7;
+// per pair of matched characters next to each other
+pub const RUN_WEIGHT: isize = 10;
+// per character before the first match
+pub const LEAD_WEIGHT: isize = -1;
+// longer lines lose one point per this many characters
+pub const LENGTH_REDUCE: isize = 10;
 
-pub const MAX_LEN: usize = 80;
+// one typo allowed per this many query characters, 0 for none
+pub const TYPO_EVERY: usize = 4;
+pub const TYPO_PENALTY: isize = 20;
 
 pub const MATCH_NUMBER: usize = 10;
 
+pub const PENDING_LEN: usize = 1024;
+
 pub const EOT: char = '\u{4}';
+pub const CTRL_C: char = '\u{3}';
+pub const CTRL_E: char = '\u{5}';
+pub const CTRL_N: char = '\u{e}';
+pub const CTRL_P: char = '\u{10}';
+pub const CTRL_Z: char = '\u{1a}';
+pub const CTRL_R: char = '\u{12}';
 pub const CTRL_U: char = '\u{15}';
+// flow control, passed through once IXON is off
+pub const CTRL_S: char = '\u{13}';
+pub const CTRL_Q: char = '\u{11}';
 
 pub const PROMPT: &'static str = "Match: ";
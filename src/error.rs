@@ -12,22 +12,61 @@
 // License for the specific language concerning governing permissions and
 // limitations under the License.
 use std::error::Error;
-use std::borrow::Borrow;
 use std::fmt::{Display, Formatter, Result};
 
+// what went wrong, which decides our exit code
+//
+// exit codes:
+//   0 - success, or cancelled by the user
+//   1 - internal error (a worker thread failed, a channel hung up)
+//   2 - terminal setup failed (no tty, termios or size unavailable, output failed)
+//   3 - terminfo unavailable or unusable
+//   4 - history could not be found or read
+//   5 - invalid input that could not be parsed
+//   6 - the selected line could not be inserted into the shell's input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Internal,
+    Terminal,
+    TermInfo,
+    History,
+    Parse,
+    Insert
+}
+
 #[derive(Debug)]
-pub struct StringError {
+pub struct BisError {
+    kind: ErrorKind,
     description: String,
-    cause: Option<Box<Error>>
+    cause: Option<Box<Error + Send>>
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ErrorKind::Internal => 1,
+            ErrorKind::Terminal => 2,
+            ErrorKind::TermInfo => 3,
+            ErrorKind::History => 4,
+            ErrorKind::Parse => 5,
+            ErrorKind::Insert => 6
+        }
+    }
 }
 
-impl Display for StringError {
+impl Display for BisError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{:?}", self)
+        // print the whole chain of causes, outermost first
+        try!(write!(f, "{}", self.description));
+
+        match self.cause {
+            None => Ok(()),
+            Some(ref error) => write!(f, ": {}", error)
+        }
     }
 }
 
-impl Error for StringError {
+impl Error for BisError {
     fn description(&self) -> &str {
         self.description.as_ref()
     }
@@ -35,16 +74,30 @@ impl Error for StringError {
     fn cause(&self) -> Option<&Error> {
         match self.cause {
             None => None,
-            Some(ref error) => Some(error.borrow())
+            Some(ref error) => Some(&**error)
         }
     }
 }
 
-impl StringError {
-    pub fn new<T: Into<String>>(description: T, cause: Option<Box<Error>>) -> StringError {
-        StringError {
+impl BisError {
+    pub fn new<T: Into<String>>(kind: ErrorKind, description: T, cause: Option<Box<Error + Send>>) -> BisError {
+        BisError {
+            kind: kind,
             description: description.into(),
             cause: cause
         }
     }
+
+    // add context to an error, keeping its kind
+    pub fn wrap<T: Into<String>>(description: T, cause: BisError) -> BisError {
+        BisError {
+            kind: cause.kind,
+            description: description.into(),
+            cause: Some(Box::new(cause))
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
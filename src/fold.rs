@@ -0,0 +1,330 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// case and accent folding
+//
+// lines are always indexed in folded form, with a map back to the
+// original characters. case sensitive query characters carry the
+// original character they have to match, so one index serves every
+// case mode.
+//
+// accents are always folded away, precomposed or combining, so "cafe"
+// matches "café" whether it was stored as NFC or NFD.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseMode {
+    // lowercase query characters match either case, uppercase only themselves
+    Smart,
+    // everything matches either case
+    Ignore,
+    // everything only matches itself
+    Respect
+}
+
+// a folded query character, and the original it must match if case sensitive
+pub type QueryChar = (char, Option<char>);
+
+// accented Latin letters and their base letters, sorted so we can binary search
+// generated from the canonical decompositions of U+00C0..U+024F and
+// U+1E00..U+1EFF, plus the letters with strokes, which don't decompose
+const ACCENTS: &'static [(char, char)] = &[
+    ('À', 'A'), ('Á', 'A'), ('Â', 'A'), ('Ã', 'A'), ('Ä', 'A'), ('Å', 'A'), ('Ç', 'C'), ('È', 'E'),
+    ('É', 'E'), ('Ê', 'E'), ('Ë', 'E'), ('Ì', 'I'), ('Í', 'I'), ('Î', 'I'), ('Ï', 'I'), ('Ñ', 'N'),
+    ('Ò', 'O'), ('Ó', 'O'), ('Ô', 'O'), ('Õ', 'O'), ('Ö', 'O'), ('Ø', 'O'), ('Ù', 'U'), ('Ú', 'U'),
+    ('Û', 'U'), ('Ü', 'U'), ('Ý', 'Y'), ('à', 'a'), ('á', 'a'), ('â', 'a'), ('ã', 'a'), ('ä', 'a'),
+    ('å', 'a'), ('ç', 'c'), ('è', 'e'), ('é', 'e'), ('ê', 'e'), ('ë', 'e'), ('ì', 'i'), ('í', 'i'),
+    ('î', 'i'), ('ï', 'i'), ('ñ', 'n'), ('ò', 'o'), ('ó', 'o'), ('ô', 'o'), ('õ', 'o'), ('ö', 'o'),
+    ('ø', 'o'), ('ù', 'u'), ('ú', 'u'), ('û', 'u'), ('ü', 'u'), ('ý', 'y'), ('ÿ', 'y'), ('Ā', 'A'),
+    ('ā', 'a'), ('Ă', 'A'), ('ă', 'a'), ('Ą', 'A'), ('ą', 'a'), ('Ć', 'C'), ('ć', 'c'), ('Ĉ', 'C'),
+    ('ĉ', 'c'), ('Ċ', 'C'), ('ċ', 'c'), ('Č', 'C'), ('č', 'c'), ('Ď', 'D'), ('ď', 'd'), ('Đ', 'D'),
+    ('đ', 'd'), ('Ē', 'E'), ('ē', 'e'), ('Ĕ', 'E'), ('ĕ', 'e'), ('Ė', 'E'), ('ė', 'e'), ('Ę', 'E'),
+    ('ę', 'e'), ('Ě', 'E'), ('ě', 'e'), ('Ĝ', 'G'), ('ĝ', 'g'), ('Ğ', 'G'), ('ğ', 'g'), ('Ġ', 'G'),
+    ('ġ', 'g'), ('Ģ', 'G'), ('ģ', 'g'), ('Ĥ', 'H'), ('ĥ', 'h'), ('Ħ', 'H'), ('ħ', 'h'), ('Ĩ', 'I'),
+    ('ĩ', 'i'), ('Ī', 'I'), ('ī', 'i'), ('Ĭ', 'I'), ('ĭ', 'i'), ('Į', 'I'), ('į', 'i'), ('İ', 'I'),
+    ('Ĵ', 'J'), ('ĵ', 'j'), ('Ķ', 'K'), ('ķ', 'k'), ('Ĺ', 'L'), ('ĺ', 'l'), ('Ļ', 'L'), ('ļ', 'l'),
+    ('Ľ', 'L'), ('ľ', 'l'), ('Ŀ', 'L'), ('ŀ', 'l'), ('Ł', 'L'), ('ł', 'l'), ('Ń', 'N'), ('ń', 'n'),
+    ('Ņ', 'N'), ('ņ', 'n'), ('Ň', 'N'), ('ň', 'n'), ('Ō', 'O'), ('ō', 'o'), ('Ŏ', 'O'), ('ŏ', 'o'),
+    ('Ő', 'O'), ('ő', 'o'), ('Ŕ', 'R'), ('ŕ', 'r'), ('Ŗ', 'R'), ('ŗ', 'r'), ('Ř', 'R'), ('ř', 'r'),
+    ('Ś', 'S'), ('ś', 's'), ('Ŝ', 'S'), ('ŝ', 's'), ('Ş', 'S'), ('ş', 's'), ('Š', 'S'), ('š', 's'),
+    ('Ţ', 'T'), ('ţ', 't'), ('Ť', 'T'), ('ť', 't'), ('Ŧ', 'T'), ('ŧ', 't'), ('Ũ', 'U'), ('ũ', 'u'),
+    ('Ū', 'U'), ('ū', 'u'), ('Ŭ', 'U'), ('ŭ', 'u'), ('Ů', 'U'), ('ů', 'u'), ('Ű', 'U'), ('ű', 'u'),
+    ('Ų', 'U'), ('ų', 'u'), ('Ŵ', 'W'), ('ŵ', 'w'), ('Ŷ', 'Y'), ('ŷ', 'y'), ('Ÿ', 'Y'), ('Ź', 'Z'),
+    ('ź', 'z'), ('Ż', 'Z'), ('ż', 'z'), ('Ž', 'Z'), ('ž', 'z'), ('ƀ', 'b'), ('Ɨ', 'I'), ('Ơ', 'O'),
+    ('ơ', 'o'), ('Ư', 'U'), ('ư', 'u'), ('Ǎ', 'A'), ('ǎ', 'a'), ('Ǐ', 'I'), ('ǐ', 'i'), ('Ǒ', 'O'),
+    ('ǒ', 'o'), ('Ǔ', 'U'), ('ǔ', 'u'), ('Ǖ', 'U'), ('ǖ', 'u'), ('Ǘ', 'U'), ('ǘ', 'u'), ('Ǚ', 'U'),
+    ('ǚ', 'u'), ('Ǜ', 'U'), ('ǜ', 'u'), ('Ǟ', 'A'), ('ǟ', 'a'), ('Ǡ', 'A'), ('ǡ', 'a'), ('Ǧ', 'G'),
+    ('ǧ', 'g'), ('Ǩ', 'K'), ('ǩ', 'k'), ('Ǫ', 'O'), ('ǫ', 'o'), ('Ǭ', 'O'), ('ǭ', 'o'), ('ǰ', 'j'),
+    ('Ǵ', 'G'), ('ǵ', 'g'), ('Ǹ', 'N'), ('ǹ', 'n'), ('Ǻ', 'A'), ('ǻ', 'a'), ('Ȁ', 'A'), ('ȁ', 'a'),
+    ('Ȃ', 'A'), ('ȃ', 'a'), ('Ȅ', 'E'), ('ȅ', 'e'), ('Ȇ', 'E'), ('ȇ', 'e'), ('Ȉ', 'I'), ('ȉ', 'i'),
+    ('Ȋ', 'I'), ('ȋ', 'i'), ('Ȍ', 'O'), ('ȍ', 'o'), ('Ȏ', 'O'), ('ȏ', 'o'), ('Ȑ', 'R'), ('ȑ', 'r'),
+    ('Ȓ', 'R'), ('ȓ', 'r'), ('Ȕ', 'U'), ('ȕ', 'u'), ('Ȗ', 'U'), ('ȗ', 'u'), ('Ș', 'S'), ('ș', 's'),
+    ('Ț', 'T'), ('ț', 't'), ('Ȟ', 'H'), ('ȟ', 'h'), ('Ȧ', 'A'), ('ȧ', 'a'), ('Ȩ', 'E'), ('ȩ', 'e'),
+    ('Ȫ', 'O'), ('ȫ', 'o'), ('Ȭ', 'O'), ('ȭ', 'o'), ('Ȯ', 'O'), ('ȯ', 'o'), ('Ȱ', 'O'), ('ȱ', 'o'),
+    ('Ȳ', 'Y'), ('ȳ', 'y'), ('ɨ', 'i'), ('Ḁ', 'A'), ('ḁ', 'a'), ('Ḃ', 'B'), ('ḃ', 'b'), ('Ḅ', 'B'),
+    ('ḅ', 'b'), ('Ḇ', 'B'), ('ḇ', 'b'), ('Ḉ', 'C'), ('ḉ', 'c'), ('Ḋ', 'D'), ('ḋ', 'd'), ('Ḍ', 'D'),
+    ('ḍ', 'd'), ('Ḏ', 'D'), ('ḏ', 'd'), ('Ḑ', 'D'), ('ḑ', 'd'), ('Ḓ', 'D'), ('ḓ', 'd'), ('Ḕ', 'E'),
+    ('ḕ', 'e'), ('Ḗ', 'E'), ('ḗ', 'e'), ('Ḙ', 'E'), ('ḙ', 'e'), ('Ḛ', 'E'), ('ḛ', 'e'), ('Ḝ', 'E'),
+    ('ḝ', 'e'), ('Ḟ', 'F'), ('ḟ', 'f'), ('Ḡ', 'G'), ('ḡ', 'g'), ('Ḣ', 'H'), ('ḣ', 'h'), ('Ḥ', 'H'),
+    ('ḥ', 'h'), ('Ḧ', 'H'), ('ḧ', 'h'), ('Ḩ', 'H'), ('ḩ', 'h'), ('Ḫ', 'H'), ('ḫ', 'h'), ('Ḭ', 'I'),
+    ('ḭ', 'i'), ('Ḯ', 'I'), ('ḯ', 'i'), ('Ḱ', 'K'), ('ḱ', 'k'), ('Ḳ', 'K'), ('ḳ', 'k'), ('Ḵ', 'K'),
+    ('ḵ', 'k'), ('Ḷ', 'L'), ('ḷ', 'l'), ('Ḹ', 'L'), ('ḹ', 'l'), ('Ḻ', 'L'), ('ḻ', 'l'), ('Ḽ', 'L'),
+    ('ḽ', 'l'), ('Ḿ', 'M'), ('ḿ', 'm'), ('Ṁ', 'M'), ('ṁ', 'm'), ('Ṃ', 'M'), ('ṃ', 'm'), ('Ṅ', 'N'),
+    ('ṅ', 'n'), ('Ṇ', 'N'), ('ṇ', 'n'), ('Ṉ', 'N'), ('ṉ', 'n'), ('Ṋ', 'N'), ('ṋ', 'n'), ('Ṍ', 'O'),
+    ('ṍ', 'o'), ('Ṏ', 'O'), ('ṏ', 'o'), ('Ṑ', 'O'), ('ṑ', 'o'), ('Ṓ', 'O'), ('ṓ', 'o'), ('Ṕ', 'P'),
+    ('ṕ', 'p'), ('Ṗ', 'P'), ('ṗ', 'p'), ('Ṙ', 'R'), ('ṙ', 'r'), ('Ṛ', 'R'), ('ṛ', 'r'), ('Ṝ', 'R'),
+    ('ṝ', 'r'), ('Ṟ', 'R'), ('ṟ', 'r'), ('Ṡ', 'S'), ('ṡ', 's'), ('Ṣ', 'S'), ('ṣ', 's'), ('Ṥ', 'S'),
+    ('ṥ', 's'), ('Ṧ', 'S'), ('ṧ', 's'), ('Ṩ', 'S'), ('ṩ', 's'), ('Ṫ', 'T'), ('ṫ', 't'), ('Ṭ', 'T'),
+    ('ṭ', 't'), ('Ṯ', 'T'), ('ṯ', 't'), ('Ṱ', 'T'), ('ṱ', 't'), ('Ṳ', 'U'), ('ṳ', 'u'), ('Ṵ', 'U'),
+    ('ṵ', 'u'), ('Ṷ', 'U'), ('ṷ', 'u'), ('Ṹ', 'U'), ('ṹ', 'u'), ('Ṻ', 'U'), ('ṻ', 'u'), ('Ṽ', 'V'),
+    ('ṽ', 'v'), ('Ṿ', 'V'), ('ṿ', 'v'), ('Ẁ', 'W'), ('ẁ', 'w'), ('Ẃ', 'W'), ('ẃ', 'w'), ('Ẅ', 'W'),
+    ('ẅ', 'w'), ('Ẇ', 'W'), ('ẇ', 'w'), ('Ẉ', 'W'), ('ẉ', 'w'), ('Ẋ', 'X'), ('ẋ', 'x'), ('Ẍ', 'X'),
+    ('ẍ', 'x'), ('Ẏ', 'Y'), ('ẏ', 'y'), ('Ẑ', 'Z'), ('ẑ', 'z'), ('Ẓ', 'Z'), ('ẓ', 'z'), ('Ẕ', 'Z'),
+    ('ẕ', 'z'), ('ẖ', 'h'), ('ẗ', 't'), ('ẘ', 'w'), ('ẙ', 'y'), ('Ạ', 'A'), ('ạ', 'a'), ('Ả', 'A'),
+    ('ả', 'a'), ('Ấ', 'A'), ('ấ', 'a'), ('Ầ', 'A'), ('ầ', 'a'), ('Ẩ', 'A'), ('ẩ', 'a'), ('Ẫ', 'A'),
+    ('ẫ', 'a'), ('Ậ', 'A'), ('ậ', 'a'), ('Ắ', 'A'), ('ắ', 'a'), ('Ằ', 'A'), ('ằ', 'a'), ('Ẳ', 'A'),
+    ('ẳ', 'a'), ('Ẵ', 'A'), ('ẵ', 'a'), ('Ặ', 'A'), ('ặ', 'a'), ('Ẹ', 'E'), ('ẹ', 'e'), ('Ẻ', 'E'),
+    ('ẻ', 'e'), ('Ẽ', 'E'), ('ẽ', 'e'), ('Ế', 'E'), ('ế', 'e'), ('Ề', 'E'), ('ề', 'e'), ('Ể', 'E'),
+    ('ể', 'e'), ('Ễ', 'E'), ('ễ', 'e'), ('Ệ', 'E'), ('ệ', 'e'), ('Ỉ', 'I'), ('ỉ', 'i'), ('Ị', 'I'),
+    ('ị', 'i'), ('Ọ', 'O'), ('ọ', 'o'), ('Ỏ', 'O'), ('ỏ', 'o'), ('Ố', 'O'), ('ố', 'o'), ('Ồ', 'O'),
+    ('ồ', 'o'), ('Ổ', 'O'), ('ổ', 'o'), ('Ỗ', 'O'), ('ỗ', 'o'), ('Ộ', 'O'), ('ộ', 'o'), ('Ớ', 'O'),
+    ('ớ', 'o'), ('Ờ', 'O'), ('ờ', 'o'), ('Ở', 'O'), ('ở', 'o'), ('Ỡ', 'O'), ('ỡ', 'o'), ('Ợ', 'O'),
+    ('ợ', 'o'), ('Ụ', 'U'), ('ụ', 'u'), ('Ủ', 'U'), ('ủ', 'u'), ('Ứ', 'U'), ('ứ', 'u'), ('Ừ', 'U'),
+    ('ừ', 'u'), ('Ử', 'U'), ('ử', 'u'), ('Ữ', 'U'), ('ữ', 'u'), ('Ự', 'U'), ('ự', 'u'), ('Ỳ', 'Y'),
+    ('ỳ', 'y'), ('Ỵ', 'Y'), ('ỵ', 'y'), ('Ỷ', 'Y'), ('ỷ', 'y'), ('Ỹ', 'Y'), ('ỹ', 'y')
+];
+
+impl CaseMode {
+    pub fn from_name<T: AsRef<str>>(name: T) -> Option<CaseMode> {
+        match name.as_ref() {
+            "smart" => Some(CaseMode::Smart),
+            "ignore" => Some(CaseMode::Ignore),
+            "respect" => Some(CaseMode::Respect),
+            _ => None
+        }
+    }
+
+    fn is_sensitive(&self, c: char) -> bool {
+        match *self {
+            CaseMode::Smart => c.is_uppercase(),
+            CaseMode::Ignore => false,
+            CaseMode::Respect => true
+        }
+    }
+
+    // regexes can only be sensitive or not as a whole
+    pub fn is_sensitive_pattern<T: AsRef<str>>(&self, pattern: T) -> bool {
+        match *self {
+            CaseMode::Smart => pattern.as_ref().chars().any(|c| {c.is_uppercase()}),
+            CaseMode::Ignore => false,
+            CaseMode::Respect => true
+        }
+    }
+}
+
+// push the full case folding of c onto out
+//
+// to_lowercase alone gets these wrong: it leaves ß alone, turns İ into
+// i plus a combining dot, and keeps ı distinct from i
+fn fold_case(c: char, out: &mut Vec<char>) {
+    match c {
+        'ß' | 'ẞ' => {
+            out.push('s');
+            out.push('s');
+        },
+        // Turkish dotted capital and dotless small i
+        'İ' | 'ı' => out.push('i'),
+        'ς' => out.push('σ'),
+        'ﬀ' => {
+            out.push('f');
+            out.push('f');
+        },
+        'ﬁ' => {
+            out.push('f');
+            out.push('i');
+        },
+        'ﬂ' => {
+            out.push('f');
+            out.push('l');
+        },
+        'ﬃ' => {
+            out.push('f');
+            out.push('f');
+            out.push('i');
+        },
+        'ﬄ' => {
+            out.push('f');
+            out.push('f');
+            out.push('l');
+        },
+        'ﬅ' | 'ﬆ' => {
+            out.push('s');
+            out.push('t');
+        },
+        _ => {
+            for lc in c.to_lowercase() {
+                out.push(lc);
+            }
+        }
+    }
+}
+
+pub fn is_combining(c: char) -> bool {
+    match c {
+        '\u{300}'...'\u{36f}' | '\u{1ab0}'...'\u{1aff}' | '\u{1dc0}'...'\u{1dff}' |
+        '\u{20d0}'...'\u{20ff}' | '\u{fe20}'...'\u{fe2f}' => true,
+        _ => false
+    }
+}
+
+// the letter without its accent, keeping its case
+fn strip_accent(c: char) -> char {
+    if (c as u32) < 0xc0 {
+        // nothing below Latin-1 has an accent
+        return c;
+    }
+
+    match ACCENTS.binary_search_by(|&(accented, _)| {accented.cmp(&c)}) {
+        Ok(idx) => ACCENTS[idx].1,
+        Err(_) => c
+    }
+}
+
+// whether an original line character satisfies a case sensitive query character
+pub fn matches_required(original: char, required: char) -> bool {
+    strip_accent(original) == required
+}
+
+// push the folded form of c onto out, which is empty for combining marks
+pub fn fold_char(c: char, out: &mut Vec<char>) {
+    if is_combining(c) {
+        return;
+    }
+
+    let start = out.len();
+    fold_case(c, out);
+
+    for f in out[start..].iter_mut() {
+        *f = strip_accent(*f);
+    }
+}
+
+// fold a query, marking the characters that have to match exactly
+pub fn fold_query<T: AsRef<str>>(query: T, mode: CaseMode) -> Vec<QueryChar> {
+    let mut result = vec![];
+    let mut folded = vec![];
+
+    for c in query.as_ref().chars() {
+        folded.clear();
+        fold_char(c, &mut folded);
+
+        let required = if mode.is_sensitive(c) {
+            Some(strip_accent(c))
+        } else {
+            None
+        };
+
+        for f in folded.iter() {
+            result.push((*f, required));
+        }
+    }
+
+    result
+}
+
+// fold a line into chars, with the index of the original character each
+// folded one came from in origin
+pub fn fold_line<T: AsRef<str>>(line: T, chars: &mut Vec<char>, origin: &mut Vec<usize>) {
+    chars.clear();
+    origin.clear();
+
+    for (idx, c) in line.as_ref().chars().enumerate() {
+        // fold straight onto the end, everything added came from idx
+        let start = chars.len();
+        fold_char(c, chars);
+        for _ in start..chars.len() {
+            origin.push(idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{ACCENTS, strip_accent};
+
+    fn fold(c: char) -> String {
+        let mut out = vec![];
+        fold_char(c, &mut out);
+        out.into_iter().collect()
+    }
+
+    #[test]
+    fn fold_char_folds_case() {
+        assert_eq!(fold('A'), "a");
+        assert_eq!(fold('ß'), "ss");
+        assert_eq!(fold('ẞ'), "ss");
+        assert_eq!(fold('İ'), "i");
+        assert_eq!(fold('ı'), "i");
+        assert_eq!(fold('ς'), "σ");
+        assert_eq!(fold('ﬃ'), "ffi");
+        assert_eq!(fold('Ж'), "ж");
+    }
+
+    #[test]
+    fn fold_query_marks_sensitive_characters() {
+        assert_eq!(fold_query("aB", CaseMode::Smart), vec![('a', None), ('b', Some('B'))]);
+        assert_eq!(fold_query("aB", CaseMode::Ignore), vec![('a', None), ('b', None)]);
+        assert_eq!(fold_query("aB", CaseMode::Respect), vec![('a', Some('a')), ('b', Some('B'))]);
+        assert_eq!(fold_query("ß", CaseMode::Respect), vec![('s', Some('ß')), ('s', Some('ß'))]);
+    }
+
+    #[test]
+    fn accents_are_sorted() {
+        for pair in ACCENTS.windows(2) {
+            assert!(pair[0].0 < pair[1].0);
+        }
+    }
+
+    #[test]
+    fn strip_accent_keeps_case() {
+        assert_eq!(strip_accent('é'), 'e');
+        assert_eq!(strip_accent('É'), 'E');
+        assert_eq!(strip_accent('ł'), 'l');
+        assert_eq!(strip_accent('ỹ'), 'y');
+        assert_eq!(strip_accent('a'), 'a');
+        assert_eq!(strip_accent('~'), '~');
+        assert_eq!(strip_accent('ж'), 'ж');
+    }
+
+    #[test]
+    fn fold_char_folds_accents() {
+        assert_eq!(fold('É'), "e");
+        assert_eq!(fold('Ø'), "o");
+        // combining marks disappear
+        assert_eq!(fold('\u{301}'), "");
+        assert!(matches_required('É', 'E'));
+        assert!(!matches_required('é', 'E'));
+    }
+
+    #[test]
+    fn fold_line_maps_back_to_the_original() {
+        let mut chars = vec![];
+        let mut origin = vec![];
+
+        // a decomposed é, then ß
+        fold_line("Cafe\u{301} ß", &mut chars, &mut origin);
+        assert_eq!(chars, vec!['c', 'a', 'f', 'e', ' ', 's', 's']);
+        assert_eq!(origin, vec![0, 1, 2, 3, 5, 6, 6]);
+    }
+}
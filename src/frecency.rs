@@ -0,0 +1,315 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// remembering what was picked
+//
+// every accepted match is appended to a log as
+// "<unix time>\t<query>\t<line>", with backslash escapes for tabs,
+// newlines and backslashes. lines picked often and recently rank higher,
+// and more so for queries that start the same way. picks are kept by the
+// normalized line, so they still count once a line is spelled differently.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Keys;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use std::env;
+
+use error::{BisError, ErrorKind};
+use config::Config;
+
+#[derive(Debug, Default)]
+pub struct Frecency {
+    // the decayed weight of each pick of a normalized line, and the query it
+    // was picked with
+    picks: HashMap<String, Vec<(f64, String)>>
+}
+
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => {
+            // the clock is before 1970, nothing will have decayed
+            0
+        }
+    }
+}
+
+// where picks are logged if the config doesn't say
+pub fn log_path(config: &Config) -> Option<PathBuf> {
+    match config.selections {
+        Some(ref path) => Some(path.clone()),
+        None => match env::var_os("XDG_DATA_HOME") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => env::home_dir().map(|home| {home.join(".local").join("share")})
+        }.map(|dir| {dir.join("bis").join("selections")})
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut escaped = false;
+    for c in text.chars() {
+        if escaped {
+            result.push(match c {
+                't' => '\t',
+                'n' => '\n',
+                other => other
+            });
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// add a pick to the log
+pub fn record(config: &Config, line: &str, query: &str) -> Result<(), BisError> {
+    let path = match log_path(config) {
+        Some(path) => path,
+        None => {
+            debug!("Nowhere to record selections");
+            return Ok(());
+        }
+    };
+
+    match path.parent() {
+        Some(dir) => match fs::create_dir_all(dir) {
+            Ok(_) => {
+                trace!("Selection log directory exists");
+            },
+            Err(e) => {
+                return Err(BisError::new(ErrorKind::History,
+                                         format!("Could not create {}", dir.display()), Some(Box::new(e))));
+            }
+        },
+        None => {}
+    }
+
+    let entry = format!("{}\t{}\t{}\n", now(), escape(query), escape(line));
+    match OpenOptions::new().append(true).create(true).open(&path).and_then(|mut f| {f.write_all(entry.as_bytes())}) {
+        Ok(_) => {
+            debug!("Recorded selection in {:?}", path);
+            Ok(())
+        },
+        Err(e) => {
+            Err(BisError::new(ErrorKind::History,
+                              format!("Could not write selection log {}", path.display()), Some(Box::new(e))))
+        }
+    }
+}
+
+impl Frecency {
+    pub fn load(config: &Config) -> Result<Frecency, BisError> {
+        let mut result = Frecency::default();
+
+        let path = match log_path(config) {
+            Some(path) => path,
+            None => {
+                debug!("No selection log location");
+                return Ok(result);
+            }
+        };
+
+        let mut content = String::new();
+        match File::open(&path).and_then(|mut f| {f.read_to_string(&mut content)}) {
+            Ok(_) => {
+                debug!("Read selection log {:?}", path);
+            },
+            Err(e) => {
+                if path.exists() {
+                    return Err(BisError::new(ErrorKind::History,
+                                             format!("Could not read selection log {}", path.display()),
+                                             Some(Box::new(e))));
+                } else {
+                    debug!("No selection log yet");
+                    return Ok(result);
+                }
+            }
+        }
+
+        result.add_entries(&content, now(), config);
+        trace!("Loaded picks for {} lines", result.picks.len());
+
+        Ok(result)
+    }
+
+    // add the picks logged in content, decayed to now
+    fn add_entries(&mut self, content: &str, now: u64, config: &Config) {
+        let half_life = config.frecency_half_life as f64 * 86400.0;
+
+        for entry in content.lines() {
+            let fields: Vec<&str> = entry.splitn(3, '\t').collect();
+            if fields.len() != 3 {
+                debug!("Skipping malformed selection: {:?}", entry);
+                continue;
+            }
+
+            let time: u64 = match fields[0].parse() {
+                Ok(t) => t,
+                Err(_) => {
+                    debug!("Skipping selection with a bad time: {:?}", entry);
+                    continue;
+                }
+            };
+
+            // each pick counts half as much every half life
+            let age = now.saturating_sub(time) as f64;
+            let weight = 0.5f64.powf(age / half_life);
+
+            let key = config.normalize.apply(&unescape(fields[2]));
+            self.picks.entry(key).or_insert(vec![]).push((weight, unescape(fields[1])));
+        }
+    }
+
+    // the normalized lines picked before
+    pub fn keys(&self) -> Keys<String, Vec<(f64, String)>> {
+        self.picks.keys()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.picks.contains_key(key)
+    }
+
+    // the bonus for the line normalized to key when searching for query
+    pub fn score(&self, key: &str, query: &str, config: &Config) -> isize {
+        let picks = match self.picks.get(key) {
+            Some(picks) => picks,
+            None => return 0
+        };
+
+        let mut total = 0.0;
+        for &(weight, ref picked_with) in picks.iter() {
+            total += weight * config.frecency_weight as f64;
+
+            // picked while typing the same thing. only the start of what was
+            // typed then counts, or an early pick of "g" would boost everything
+            if !query.is_empty() && picked_with.starts_with(query) {
+                total += weight * config.prefix_weight as f64;
+            }
+        }
+
+        total.round() as isize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{escape, unescape};
+    use config::Config;
+    use normalize::Normalize;
+
+    const DAY: u64 = 86400;
+
+    fn frecency(content: &str, now: u64) -> Frecency {
+        let mut result = Frecency::default();
+        result.add_entries(content, now, &Config::default());
+        result
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let text = "printf 'a\\tb\\n'\tand\na tab";
+        assert_eq!(escape(text), "printf 'a\\\\tb\\\\n'\\tand\\na tab");
+        assert!(!escape(text).contains('\t') && !escape(text).contains('\n'));
+        assert_eq!(unescape(&escape(text)), text);
+
+        // lines are kept normalized, so leave them as they are
+        let mut config = Config::default();
+        config.normalize = Normalize::from_names(&["none"]).unwrap();
+        let mut picks = Frecency::default();
+        let entry = format!("{}\t{}\t{}", 10 * DAY, escape("q\tx"), escape(text));
+        picks.add_entries(&entry, 10 * DAY, &config);
+        assert_eq!(picks.picks.get(text), Some(&vec![(1.0, "q\tx".to_owned())]));
+    }
+
+    #[test]
+    fn picks_decay_by_half_life() {
+        let config = Config::default();
+        let half_life = config.frecency_half_life * DAY;
+        let now = 100 * DAY;
+
+        let fresh = frecency(&format!("{}\tx\tls", now), now);
+        let old = frecency(&format!("{}\tx\tls", now - half_life), now);
+        let older = frecency(&format!("{}\tx\tls", now - 2 * half_life), now);
+        assert_eq!(fresh.score("ls", "", &config), config.frecency_weight);
+        assert_eq!(old.score("ls", "", &config), config.frecency_weight / 2);
+        assert_eq!(older.score("ls", "", &config), config.frecency_weight / 4);
+
+        // picks from the future count in full
+        let future = frecency(&format!("{}\tx\tls", now + DAY), now);
+        assert_eq!(future.score("ls", "", &config), config.frecency_weight);
+
+        // and they add up
+        let twice = frecency(&format!("{}\tx\tls\n{}\tx\tls", now, now), now);
+        assert_eq!(twice.score("ls", "", &config), 2 * config.frecency_weight);
+        assert_eq!(twice.score("cd", "", &config), 0);
+    }
+
+    #[test]
+    fn picks_are_kept_normalized() {
+        let config = Config::default();
+        let picks = frecency(&format!("{}\tx\t  git  status;", DAY), DAY);
+        assert!(picks.contains("git status"));
+        assert_eq!(picks.score("git status", "", &config), config.frecency_weight);
+    }
+
+    #[test]
+    fn boosts_queries_that_start_the_same() {
+        let config = Config::default();
+        let picks = frecency(&format!("{}\tgit st\tgit status", DAY), DAY);
+
+        let plain = config.frecency_weight;
+        let boosted = config.frecency_weight + config.prefix_weight;
+        assert_eq!(picks.score("git status", "", &config), plain);
+        assert_eq!(picks.score("git status", "git", &config), boosted);
+        assert_eq!(picks.score("git status", "git st", &config), boosted);
+        // typing more than was typed then, or something else, gets no boost
+        assert_eq!(picks.score("git status", "git sta", &config), plain);
+        assert_eq!(picks.score("git status", "st", &config), plain);
+    }
+
+    #[test]
+    fn skips_malformed_entries() {
+        let config = Config::default();
+        let content = format!("{now}\tls\n\
+                               yesterday\tls\tls\n\
+                               \n\
+                               {now}\tl\tls\n\
+                               -5\tls\tls\n", now = DAY);
+        let picks = frecency(&content, DAY);
+        assert_eq!(picks.picks.len(), 1);
+        assert_eq!(picks.score("ls", "l", &config), config.frecency_weight + config.prefix_weight);
+    }
+}
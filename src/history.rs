@@ -0,0 +1,427 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// finding and reading shell history files
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use std::env;
+
+use libc;
+
+use error::{BisError, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub text: String,
+    // unix time it was run, if the history records it
+    pub time: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    // one command per line, optionally with #<timestamp> lines
+    Plain,
+    // zsh's ": <timestamp>:<duration>;<command>"
+    ZshExtended,
+    // fish's "- cmd: <command>" entries
+    Fish
+}
+
+impl Shell {
+    fn from_name<T: AsRef<str>>(name: T) -> Option<Shell> {
+        // login shells show up as "-bash". sh is most often bash in posix
+        // mode, which keeps the same history. the sh's that aren't keep none
+        match name.as_ref().trim().trim_left_matches('-') {
+            "bash" | "sh" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None
+        }
+    }
+
+    // where this shell keeps its history if nothing says otherwise
+    fn default_history(&self) -> Option<PathBuf> {
+        match *self {
+            Shell::Bash => env::home_dir().map(|home| {home.join(".bash_history")}),
+            Shell::Zsh => {
+                match env::var_os("ZDOTDIR") {
+                    Some(dir) => Some(PathBuf::from(dir)),
+                    None => env::home_dir()
+                }.map(|dir| {dir.join(".zsh_history")})
+            },
+            Shell::Fish => {
+                match env::var_os("XDG_DATA_HOME") {
+                    Some(dir) => Some(PathBuf::from(dir)),
+                    None => env::home_dir().map(|home| {home.join(".local").join("share")})
+                }.map(|dir| {dir.join("fish").join("fish_history")})
+            }
+        }
+    }
+}
+
+// the shell that started us, or failing that the user's login shell
+pub fn detect_shell() -> Option<Shell> {
+    let ppid = unsafe {libc::getppid()};
+    let comm_path = format!("/proc/{}/comm", ppid);
+
+    let mut comm = String::new();
+    match File::open(&comm_path).and_then(|mut f| {f.read_to_string(&mut comm)}) {
+        Ok(_) => {
+            trace!("Parent process is {:?}", comm);
+            match Shell::from_name(&comm) {
+                Some(shell) => return Some(shell),
+                None => {
+                    debug!("Parent process is not a shell we know");
+                }
+            }
+        },
+        Err(e) => {
+            debug!("Could not read {}: {}", comm_path, e);
+        }
+    }
+
+    match env::var_os("SHELL") {
+        Some(shell) => {
+            trace!("SHELL is {:?}", shell);
+            Path::new(&shell).file_name().and_then(|name| {name.to_str()}).and_then(Shell::from_name)
+        },
+        None => None
+    }
+}
+
+// the history files to read, oldest first
+pub fn discover(explicit: &[PathBuf]) -> Result<Vec<PathBuf>, BisError> {
+    if !explicit.is_empty() {
+        debug!("Using history files given on the command line");
+        return Ok(explicit.to_vec());
+    }
+
+    let shell = detect_shell();
+    debug!("Detected shell: {:?}", shell);
+
+    // HISTFILE is only there if the user exported it
+    if shell != Some(Shell::Fish) {
+        match env::var_os("HISTFILE") {
+            Some(path) => {
+                trace!("Got history path from HISTFILE: {:?}", path);
+                return Ok(vec![PathBuf::from(path)]);
+            },
+            None => {
+                debug!("HISTFILE is not set");
+            }
+        }
+    }
+
+    // try our shell first, then the others
+    let mut candidates = vec![];
+    match shell {
+        Some(s) => candidates.push(s),
+        None => {}
+    }
+    for s in [Shell::Bash, Shell::Zsh, Shell::Fish].iter() {
+        if !candidates.contains(s) {
+            candidates.push(*s);
+        }
+    }
+
+    for s in candidates.iter() {
+        match s.default_history() {
+            Some(ref path) if path.is_file() => {
+                debug!("Using {:?} history at {:?}", s, path);
+                return Ok(vec![path.clone()]);
+            },
+            Some(path) => {
+                trace!("No history at {:?}", path);
+            },
+            None => {
+                trace!("No default history location for {:?}", s);
+            }
+        }
+    }
+
+    Err(BisError::new(ErrorKind::History,
+                      "Could not find a history file, set HISTFILE or use --history PATH", None))
+}
+
+// zsh stores some bytes escaped with a 0x83 marker, in either format
+fn unmetafy(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut meta = false;
+
+    for b in bytes.iter() {
+        if meta {
+            result.push(*b ^ 32);
+            meta = false;
+        } else if *b == 0x83 {
+            meta = true;
+        } else {
+            result.push(*b);
+        }
+    }
+
+    result
+}
+
+fn detect_format(content: &str) -> Format {
+    for line in content.lines() {
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with("- cmd: ") {
+            return Format::Fish;
+        } else if strip_zsh_prefix(line).is_some() {
+            return Format::ZshExtended;
+        } else {
+            return Format::Plain;
+        }
+    }
+
+    Format::Plain
+}
+
+fn strip_zsh_prefix(line: &str) -> Option<&str> {
+    zsh_entry(line).map(|(_, command)| {command})
+}
+
+// the timestamp and command of a zsh extended history line
+fn zsh_entry(line: &str) -> Option<(Option<u64>, &str)> {
+    if !line.starts_with(": ") {
+        return None;
+    }
+
+    match line.find(';') {
+        Some(idx) => {
+            // everything between ": " and ";" is "<timestamp>:<duration>"
+            let meta = &line[2..idx];
+            if !meta.is_empty() && meta.chars().all(|c| {c.is_digit(10) || c == ':'}) {
+                let time = meta.split(':').next().and_then(|t| {t.parse().ok()});
+                Some((time, &line[idx + 1..]))
+            } else {
+                None
+            }
+        },
+        None => None
+    }
+}
+
+// fish writes backslashes and newlines in commands escaped
+fn fish_unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut escaped = false;
+    for c in text.chars() {
+        if escaped {
+            match c {
+                'n' => result.push('\n'),
+                '\\' => result.push('\\'),
+                other => {
+                    result.push('\\');
+                    result.push(other);
+                }
+            }
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else {
+            result.push(c);
+        }
+    }
+    if escaped {
+        result.push('\\');
+    }
+    result
+}
+
+// bash's "#<timestamp>" lines
+fn parse_timestamp(line: &str) -> Option<u64> {
+    if line.len() > 1 && line.starts_with('#') && line[1..].chars().all(|c| {c.is_digit(10)}) {
+        line[1..].parse().ok()
+    } else {
+        None
+    }
+}
+
+// read the commands in a history file, oldest first
+pub fn read_commands<T: AsRef<Path>>(path: T) -> Result<Vec<Command>, BisError> {
+    let mut bytes = vec![];
+    match File::open(path.as_ref()).and_then(|mut f| {f.read_to_end(&mut bytes)}) {
+        Ok(_) => {
+            trace!("Read {} bytes of history", bytes.len());
+        },
+        Err(e) => {
+            return Err(BisError::new(ErrorKind::History,
+                                     format!("Could not read history file {}", path.as_ref().display()),
+                                     Some(Box::new(e))));
+        }
+    }
+
+    let mut content = String::from_utf8_lossy(&bytes).into_owned();
+    let format = detect_format(&content);
+    debug!("History format: {:?}", format);
+
+    if format == Format::ZshExtended {
+        content = String::from_utf8_lossy(&unmetafy(&bytes)).into_owned();
+    } else if format == Format::Plain && String::from_utf8(bytes.clone()).is_err() {
+        // metafying utf-8 always leaves stray continuation bytes, so a
+        // plain history that's only utf-8 unmetafied is zsh's
+        match String::from_utf8(unmetafy(&bytes)) {
+            Ok(unmetafied) => {
+                debug!("Reading a plain zsh history");
+                content = unmetafied;
+            },
+            Err(_) => {
+                trace!("History is not utf-8");
+            }
+        }
+    }
+
+    let mut commands: Vec<Command> = vec![];
+    // the time the next or current command was run
+    let mut time = None;
+
+    for line in content.lines() {
+        match format {
+            Format::Plain => {
+                match parse_timestamp(line) {
+                    Some(t) => time = Some(t),
+                    None => {
+                        commands.push(Command {
+                            text: line.to_owned(),
+                            time: time
+                        });
+                        time = None;
+                    }
+                }
+            },
+            Format::ZshExtended => {
+                // zsh writes each newline in a command as a backslash and a
+                // newline, the rest of the command has no prefix
+                match commands.last_mut() {
+                    Some(ref mut command) if command.text.ends_with('\\') => {
+                        command.text.pop();
+                        command.text.push('\n');
+                        command.text.push_str(line);
+                        continue;
+                    },
+                    _ => {}
+                }
+
+                let text = match zsh_entry(line) {
+                    Some((t, command)) => {
+                        time = t;
+                        command
+                    },
+                    None => line
+                };
+                commands.push(Command {
+                    text: text.to_owned(),
+                    time: time
+                });
+            },
+            Format::Fish => {
+                // the other keys ("when", "paths") are indented
+                if line.starts_with("- cmd: ") {
+                    commands.push(Command {
+                        text: fish_unescape(&line["- cmd: ".len()..]),
+                        time: None
+                    });
+                } else if line.trim_left().starts_with("when: ") {
+                    match commands.last_mut() {
+                        Some(command) => {
+                            command.time = line.trim_left()["when: ".len()..].trim().parse().ok();
+                        },
+                        None => {
+                            debug!("Fish history has a time before any command");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+
+    use super::*;
+    use super::unmetafy;
+
+    // the commands in a history file holding bytes
+    fn read(name: &str, bytes: &[u8]) -> Vec<(String, Option<u64>)> {
+        let path = env::temp_dir().join(format!("bis-test-{}", name));
+        File::create(&path).and_then(|mut f| {f.write_all(bytes)}).unwrap();
+        let commands = read_commands(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        commands.into_iter().map(|Command {text, time}| {(text, time)}).collect()
+    }
+
+    #[test]
+    fn unmetafy_restores_bytes() {
+        assert_eq!(unmetafy(b"ls"), b"ls".to_vec());
+        assert_eq!(unmetafy(&[b'a', 0x83, 0xa3, b'b']), vec![b'a', 0x83, b'b']);
+        assert_eq!(unmetafy(&[0x83, 0x20]), vec![0]);
+    }
+
+    #[test]
+    fn bash_timestamps() {
+        assert_eq!(read("bash", b"#1500000000\ngit status\nls\n"),
+                   vec![("git status".to_owned(), Some(1500000000)), ("ls".to_owned(), None)]);
+    }
+
+    #[test]
+    fn bash_utf8_is_not_unmetafied() {
+        // the second byte of "ă" is 0x83
+        assert_eq!(read("bash-utf8", "echo ă\n".as_bytes()), vec![("echo ă".to_owned(), None)]);
+    }
+
+    #[test]
+    fn zsh_continuation_lines() {
+        let history = b": 1500000000:0;for i in a b\\\ndo echo $i\\\ndone\n: 1500000001:2;ls\n";
+        assert_eq!(read("zsh-continued", history),
+                   vec![("for i in a b\ndo echo $i\ndone".to_owned(), Some(1500000000)),
+                        ("ls".to_owned(), Some(1500000001))]);
+    }
+
+    #[test]
+    fn zsh_metafied() {
+        // "ă" is c4 83, which zsh writes as c4 83 a3
+        assert_eq!(read("zsh-extended-meta", b": 1500000000:0;echo \xc4\x83\xa3\n"),
+                   vec![("echo ă".to_owned(), Some(1500000000))]);
+        assert_eq!(read("zsh-plain-meta", b"echo \xc4\x83\xa3\nls\n"),
+                   vec![("echo ă".to_owned(), None), ("ls".to_owned(), None)]);
+    }
+
+    #[test]
+    fn fish_escapes() {
+        let history = b"- cmd: echo a\\nb \\\\ c\n  when: 1500000000\n- cmd: ls\n  when: 1500000001\n";
+        assert_eq!(read("fish", history),
+                   vec![("echo a\nb \\ c".to_owned(), Some(1500000000)),
+                        ("ls".to_owned(), Some(1500000001))]);
+    }
+}
@@ -0,0 +1,609 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// the line index, stored by column
+//
+// every line's text, folded characters, positions and heat are appended to
+// a few shared buffers instead of each line owning a map and vectors of its
+// own. a line only keeps where its part of each buffer starts, in u32. keys,
+// positions and heat are stored in u16, u16 and i8. lines too long or too
+// hot for that, or with characters past u16, go in the wide columns
+// instead, so scores come out the same either way.
+//
+// each line also has a 64 bit signature of the characters in it, so lines
+// missing something the query needs are skipped before any matching.
+
+use std::cmp;
+use std::mem;
+use std::u16;
+use std::u32;
+use std::i8;
+
+use config::Config;
+use fold::fold_char;
+use scorer::Scorer;
+
+#[derive(Debug, Default)]
+pub struct Index {
+    // every line's text, one after another
+    text: String,
+    // the distinct folded characters of each line in order, and where the
+    // positions of each one end
+    keys: Vec<u16>,
+    wide_keys: Vec<char>,
+    ends: Vec<u16>,
+    wide_ends: Vec<u32>,
+    // the folded positions of each key, in increasing order
+    positions: Vec<u16>,
+    wide_positions: Vec<u32>,
+    // the heat at each folded position
+    heat: Vec<i8>,
+    wide_heat: Vec<isize>,
+    // the original char index of each folded position, for lines folding changed
+    origin: Vec<u16>,
+    wide_origin: Vec<u32>
+}
+
+// where a line's parts of the index are, each column stays under 4GB
+#[derive(Debug, Clone, Copy)]
+pub struct Span {
+    text: u32,
+    text_len: u32,
+    keys: u32,
+    keys_len: u32,
+    positions: u32,
+    heat: u32,
+    // the number of folded characters
+    folded_len: u32,
+    origin: Option<u32>,
+    wide: bool,
+    // which characters the folded line has
+    signature: u64
+}
+
+// one line of the index
+pub struct Line<'a> {
+    index: &'a Index,
+    span: &'a Span
+}
+
+// the signature bit of a folded character. letters and digits get a bit
+// each, everything else shares the rest
+pub fn char_bit(c: char) -> u64 {
+    // ascii is checked first, it's almost everything
+    match c {
+        'a'...'z' => 1 << (c as u32 - 'a' as u32),
+        '0'...'9' => 1 << (26 + c as u32 - '0' as u32),
+        '\0'...'\u{7f}' => 1 << (36 + c as u32 % 14),
+        _ => 1 << (50 + c as u32 % 14)
+    }
+}
+
+// part of a narrow or wide column
+#[derive(Clone, Copy)]
+enum Column<'a> {
+    Narrow(&'a [u16]),
+    Wide(&'a [u32])
+}
+
+impl<'a> Column<'a> {
+    fn get(&self, idx: usize) -> usize {
+        match *self {
+            Column::Narrow(values) => values[idx] as usize,
+            Column::Wide(values) => values[idx] as usize
+        }
+    }
+
+    fn binary_search(&self, value: usize) -> Result<usize, usize> {
+        match *self {
+            Column::Narrow(values) => {
+                if value > u16::MAX as usize {
+                    Err(values.len())
+                } else {
+                    values.binary_search(&(value as u16))
+                }
+            },
+            Column::Wide(values) => values.binary_search(&(value as u32))
+        }
+    }
+}
+
+impl Index {
+    // add a line, returning where it went
+    pub fn push(&mut self, text: &str, scorer: &Scorer, config: &Config) -> Span {
+        let line: Vec<char> = text.chars().collect();
+        let line_heat = scorer.heatmap(&line, config);
+
+        let mut heat = vec![];
+        let mut origin = vec![];
+        let mut chars = vec![];
+        let mut folded = vec![];
+        let mut identity = true;
+        let mut signature = 0;
+
+        for (idx, c) in line.iter().enumerate() {
+            // index the folded form, case sensitive queries check the original
+            folded.clear();
+            fold_char(*c, &mut folded);
+            if folded.len() != 1 {
+                identity = false;
+            }
+
+            for f in folded.iter() {
+                // don't map whitespace
+                if !f.is_whitespace() {
+                    chars.push((*f, heat.len()));
+                    signature |= char_bit(*f);
+                }
+
+                heat.push(line_heat[idx]);
+                origin.push(idx);
+            }
+        }
+
+        // group positions by character, the sort is stable so they stay in order
+        chars.sort_by(|a, b| {a.0.cmp(&b.0)});
+
+        // sorted, so the last key is the largest
+        let wide = cmp::max(line.len(), heat.len()) > u16::MAX as usize ||
+            heat.iter().any(|h| {*h < i8::MIN as isize || *h > i8::MAX as isize}) ||
+            chars.last().map(|&(c, _)| {c as u32 > u16::MAX as u32}).unwrap_or(false);
+
+        let mut span = self.end(wide, identity);
+        span.text_len = text.len() as u32;
+        span.folded_len = heat.len() as u32;
+        span.signature = signature;
+
+        self.text.push_str(text);
+
+        let mut keys_len = 0;
+        for (i, &(c, pos)) in chars.iter().enumerate() {
+            if wide {
+                self.wide_positions.push(pos as u32);
+            } else {
+                self.positions.push(pos as u16);
+            }
+
+            // the last position of this key
+            if i + 1 == chars.len() || chars[i + 1].0 != c {
+                keys_len += 1;
+                if wide {
+                    self.wide_keys.push(c);
+                    self.wide_ends.push(i as u32 + 1);
+                } else {
+                    self.keys.push(c as u16);
+                    self.ends.push(i as u16 + 1);
+                }
+            }
+        }
+
+        if wide {
+            trace!("Indexing a wide line of {} characters", line.len());
+            self.wide_heat.extend(heat.into_iter());
+            if !identity {
+                self.wide_origin.extend(origin.into_iter().map(|idx| {idx as u32}));
+            }
+        } else {
+            self.heat.extend(heat.into_iter().map(|h| {h as i8}));
+            if !identity {
+                self.origin.extend(origin.into_iter().map(|idx| {idx as u16}));
+            }
+        }
+
+        span.keys_len = keys_len;
+        span
+    }
+
+    // a span for a line added after everything so far
+    fn end(&self, wide: bool, identity: bool) -> Span {
+        Span {
+            text: self.text.len() as u32,
+            text_len: 0,
+            keys: if wide {
+                self.wide_keys.len()
+            } else {
+                self.keys.len()
+            } as u32,
+            keys_len: 0,
+            positions: if wide {
+                self.wide_positions.len()
+            } else {
+                self.positions.len()
+            } as u32,
+            heat: if wide {
+                self.wide_heat.len()
+            } else {
+                self.heat.len()
+            } as u32,
+            folded_len: 0,
+            origin: if identity {
+                None
+            } else if wide {
+                Some(self.wide_origin.len() as u32)
+            } else {
+                Some(self.origin.len() as u32)
+            },
+            wide: wide,
+            signature: 0
+        }
+    }
+
+    // whether text can be added without a column passing u32 offsets.
+    // folding makes a line at most three times longer
+    pub fn has_room(&self, text: &str) -> bool {
+        let longest = [self.text.len(), self.keys.len(), self.wide_keys.len(), self.positions.len(),
+                       self.wide_positions.len(), self.heat.len(), self.wide_heat.len(), self.origin.len(),
+                       self.wide_origin.len()].iter().cloned().max().unwrap_or(0);
+        longest as u64 + 3 * text.len() as u64 <= u32::MAX as u64
+    }
+
+    // copy a line from another index, returning where it went
+    pub fn copy(&mut self, from: &Index, span: &Span) -> Span {
+        let mut copied = self.end(span.wide, span.origin.is_none());
+        copied.text_len = span.text_len;
+        copied.keys_len = span.keys_len;
+        copied.folded_len = span.folded_len;
+        copied.signature = span.signature;
+
+        let text = span.text as usize;
+        self.text.push_str(&from.text[text..text + span.text_len as usize]);
+
+        let keys = span.keys as usize..(span.keys + span.keys_len) as usize;
+        let positions = span.positions as usize;
+        let heat = span.heat as usize..(span.heat + span.folded_len) as usize;
+        let origin = span.origin.map(|start| {start as usize..(start + span.folded_len) as usize});
+        if span.wide {
+            // the last key's end is how many positions there are
+            let count = if keys.end > keys.start {
+                from.wide_ends[keys.end - 1] as usize
+            } else {
+                0
+            };
+            self.wide_keys.extend(from.wide_keys[keys.clone()].iter().cloned());
+            self.wide_ends.extend(from.wide_ends[keys].iter().cloned());
+            self.wide_positions.extend(from.wide_positions[positions..positions + count].iter().cloned());
+            self.wide_heat.extend(from.wide_heat[heat].iter().cloned());
+            match origin {
+                Some(origin) => self.wide_origin.extend(from.wide_origin[origin].iter().cloned()),
+                None => {}
+            }
+        } else {
+            let count = if keys.end > keys.start {
+                from.ends[keys.end - 1] as usize
+            } else {
+                0
+            };
+            self.keys.extend(from.keys[keys.clone()].iter().cloned());
+            self.ends.extend(from.ends[keys].iter().cloned());
+            self.positions.extend(from.positions[positions..positions + count].iter().cloned());
+            self.heat.extend(from.heat[heat].iter().cloned());
+            match origin {
+                Some(origin) => self.origin.extend(from.origin[origin].iter().cloned()),
+                None => {}
+            }
+        }
+
+        copied
+    }
+
+    pub fn line<'a>(&'a self, span: &'a Span) -> Line<'a> {
+        Line {
+            index: self,
+            span: span
+        }
+    }
+
+    // give back what growing the buffers left unused
+    pub fn shrink_to_fit(&mut self) {
+        self.text.shrink_to_fit();
+        self.keys.shrink_to_fit();
+        self.wide_keys.shrink_to_fit();
+        self.ends.shrink_to_fit();
+        self.wide_ends.shrink_to_fit();
+        self.positions.shrink_to_fit();
+        self.wide_positions.shrink_to_fit();
+        self.heat.shrink_to_fit();
+        self.wide_heat.shrink_to_fit();
+        self.origin.shrink_to_fit();
+        self.wide_origin.shrink_to_fit();
+    }
+
+    // bytes allocated for the buffers
+    pub fn memory(&self) -> usize {
+        self.text.capacity() +
+            self.keys.capacity() * mem::size_of::<u16>() +
+            self.wide_keys.capacity() * mem::size_of::<char>() +
+            self.ends.capacity() * mem::size_of::<u16>() +
+            self.wide_ends.capacity() * mem::size_of::<u32>() +
+            self.positions.capacity() * mem::size_of::<u16>() +
+            self.wide_positions.capacity() * mem::size_of::<u32>() +
+            self.heat.capacity() * mem::size_of::<i8>() +
+            self.wide_heat.capacity() * mem::size_of::<isize>() +
+            self.origin.capacity() * mem::size_of::<u16>() +
+            self.wide_origin.capacity() * mem::size_of::<u32>()
+    }
+}
+
+impl<'a> Line<'a> {
+    pub fn text(&self) -> &'a str {
+        let start = self.span.text as usize;
+        &self.index.text[start..start + self.span.text_len as usize]
+    }
+
+    pub fn signature(&self) -> u64 {
+        self.span.signature
+    }
+
+    // the number of folded characters
+    pub fn len(&self) -> usize {
+        self.span.folded_len as usize
+    }
+
+    // the heat at each folded position, narrow heat is widened into buffer
+    pub fn heat<'b>(&self, buffer: &'b mut Vec<isize>) -> &'b [isize] where 'a: 'b {
+        let start = self.span.heat as usize;
+        let end = start + self.len();
+        if self.span.wide {
+            &self.index.wide_heat[start..end]
+        } else {
+            buffer.clear();
+            buffer.extend(self.index.heat[start..end].iter().map(|h| {*h as isize}));
+            buffer
+        }
+    }
+
+    // add the folded positions of c to out, in increasing order
+    pub fn positions(&self, c: char, out: &mut Vec<usize>) {
+        let keys = self.span.keys as usize;
+        let range = keys..keys + self.span.keys_len as usize;
+        let found = if self.span.wide {
+            self.index.wide_keys[range].binary_search(&c)
+        } else if c as u32 > u16::MAX as u32 {
+            return;
+        } else {
+            self.index.keys[range].binary_search(&(c as u16))
+        };
+        let key = match found {
+            Ok(key) => keys + key,
+            Err(_) => return
+        };
+
+        let ends = if self.span.wide {
+            Column::Wide(&self.index.wide_ends)
+        } else {
+            Column::Narrow(&self.index.ends)
+        };
+        let start = if key == keys {
+            0
+        } else {
+            ends.get(key - 1)
+        };
+        let end = ends.get(key);
+
+        let base = self.span.positions as usize;
+        if self.span.wide {
+            out.extend(self.index.wide_positions[base + start..base + end].iter().map(|pos| {*pos as usize}));
+        } else {
+            out.extend(self.index.positions[base + start..base + end].iter().map(|pos| {*pos as usize}));
+        }
+    }
+
+    fn origin(&self) -> Option<Column<'a>> {
+        self.span.origin.map(|start| {
+            let start = start as usize;
+            let end = start + self.len();
+            if self.span.wide {
+                Column::Wide(&self.index.wide_origin[start..end])
+            } else {
+                Column::Narrow(&self.index.origin[start..end])
+            }
+        })
+    }
+
+    // the first folded position of an original char index
+    pub fn folded(&self, pos: usize) -> usize {
+        match self.origin() {
+            None => pos,
+            Some(origin) => match origin.binary_search(pos) {
+                Ok(mut idx) => {
+                    while idx > 0 && origin.get(idx - 1) == pos {
+                        idx -= 1;
+                    }
+                    idx
+                },
+                // a combining mark, which folded into nothing
+                Err(idx) => idx
+            }
+        }
+    }
+
+    // the original char index of a folded position
+    pub fn original(&self, pos: usize) -> usize {
+        match self.origin() {
+            None => pos,
+            Some(origin) => origin.get(pos)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use std::iter;
+
+    use super::*;
+    use config::Config;
+    use fold::fold_char;
+    use scorer::Scorer;
+    use search::Occurrences;
+
+    // a line as it was indexed before the index was stored by column, with a
+    // map and vectors of its own, kept to check the columns against
+    // and to measure them against in the benchmarks
+    pub struct HashLine {
+        pub text: String,
+        pub char_map: HashMap<char, Vec<u32>>,
+        pub heatmap: Vec<isize>,
+        // empty if folding didn't change the line
+        pub origin: Vec<usize>,
+        pub factor: isize,
+        pub occurrences: Occurrences
+    }
+
+    impl HashLine {
+        pub fn new(text: String, factor: isize, scorer: &Scorer, config: &Config) -> HashLine {
+            let mut map: HashMap<char, Vec<u32>> = HashMap::new();
+            let mut heat = vec![];
+            let mut origin = vec![];
+            let mut folded = vec![];
+            let mut identity = true;
+
+            let line: Vec<char> = text.chars().collect();
+            let line_heat = scorer.heatmap(&line, config);
+
+            for (idx, c) in line.iter().enumerate() {
+                folded.clear();
+                fold_char(*c, &mut folded);
+                if folded.len() != 1 {
+                    identity = false;
+                }
+
+                for f in folded.iter() {
+                    if !f.is_whitespace() {
+                        map.entry(*f).or_insert(Vec::new()).push(heat.len() as u32);
+                    }
+                    heat.push(line_heat[idx]);
+                    origin.push(idx);
+                }
+            }
+
+            if identity {
+                origin = vec![];
+            }
+
+            HashLine {
+                text: text,
+                char_map: map,
+                heatmap: heat,
+                origin: origin,
+                factor: factor,
+                occurrences: Occurrences {
+                    count: 1,
+                    first: factor,
+                    last: factor,
+                    first_time: None,
+                    last_time: None
+                }
+            }
+        }
+    }
+
+    // the same lines as the index used to hold, long and wide ones too
+    fn history() -> Vec<String> {
+        // too long for u16 positions
+        let long: String = iter::repeat("ab ").take(30000).collect();
+        vec![
+            "git status".to_owned(),
+            "ls -la  ~/src".to_owned(),
+            "café ŒUVRE straße".to_owned(),
+            "cafe\u{301} noe\u{308}l".to_owned(),
+            // past u16 keys
+            "echo \u{1f389} done".to_owned(),
+            "  leading and trailing  ".to_owned(),
+            long,
+            "".to_owned()
+        ]
+    }
+
+    fn check(line: &Line, text: &str, old: &HashLine) {
+        let mut heat = vec![];
+        let mut positions = vec![];
+
+        assert_eq!(line.text(), text);
+        assert_eq!(line.len(), old.heatmap.len());
+        assert_eq!(line.heat(&mut heat), &old.heatmap[..]);
+
+        for (c, list) in old.char_map.iter() {
+            positions.clear();
+            line.positions(*c, &mut positions);
+            let expected: Vec<usize> = list.iter().map(|pos| {*pos as usize}).collect();
+            assert_eq!(positions, expected);
+        }
+        for c in ['q', 'é', '\u{1f388}', 'Z', ' '].iter() {
+            if !old.char_map.contains_key(c) {
+                positions.clear();
+                line.positions(*c, &mut positions);
+                assert!(positions.is_empty());
+            }
+        }
+
+        for pos in 0..old.heatmap.len() {
+            let expected = if old.origin.is_empty() {
+                pos
+            } else {
+                old.origin[pos]
+            };
+            assert_eq!(line.original(pos), expected);
+        }
+        for idx in 0..text.chars().count() {
+            // a combining mark folds into nothing, and maps to what's next
+            let expected = if old.origin.is_empty() {
+                idx
+            } else {
+                old.origin.iter().position(|o| {*o >= idx}).unwrap_or(old.origin.len())
+            };
+            assert_eq!(line.folded(idx), expected);
+        }
+    }
+
+    #[test]
+    fn columns_match_map_per_line() {
+        let config = Config::default();
+        let scorer = config.scorer.create();
+        let history = history();
+
+        let mut index = Index::default();
+        let spans: Vec<Span> = history.iter().map(|text| {index.push(text, &*scorer, &config)}).collect();
+
+        for (text, span) in history.iter().zip(spans.iter()) {
+            let old = HashLine::new(text.clone(), 0, &*scorer, &config);
+            check(&index.line(span), text, &old);
+        }
+    }
+
+    #[test]
+    fn copies_match_the_original() {
+        let config = Config::default();
+        let scorer = config.scorer.create();
+        let history = history();
+
+        let mut index = Index::default();
+        let spans: Vec<Span> = history.iter().map(|text| {index.push(text, &*scorer, &config)}).collect();
+
+        // backwards, so every line lands somewhere else
+        let mut copy = Index::default();
+        let copies: Vec<Span> = spans.iter().rev().map(|span| {copy.copy(&index, span)}).collect();
+
+        for ((text, span), copied) in history.iter().zip(spans.iter()).rev().zip(copies.iter()) {
+            let old = HashLine::new(text.clone(), 0, &*scorer, &config);
+            check(&copy.line(copied), text, &old);
+            assert_eq!(copy.line(copied).signature(), index.line(span).signature());
+        }
+
+        // nothing more, nothing less
+        index.shrink_to_fit();
+        copy.shrink_to_fit();
+        assert_eq!(copy.memory(), index.memory());
+    }
+}
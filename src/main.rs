@@ -18,38 +18,167 @@
 #![feature(iter_arith)]
 #![feature(io)]
 #![feature(mpsc_select)]
+#![cfg_attr(test, feature(test))]
+#![cfg_attr(all(test, feature = "count-allocs"), feature(alloc_system))]
+#[cfg(all(test, feature = "count-allocs"))]
+extern crate alloc_system;
 extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 extern crate term;
 extern crate unicode_width;
+extern crate regex;
+#[cfg(test)]
+extern crate test;
+
+use std::io::prelude::*;
+
+use std::io;
+use std::process;
+use std::path::PathBuf;
 
 use ui::UI;
+use config::Config;
+use error::{BisError, ErrorKind};
+use search::{SearchBase, SearchMode, Scratch};
 
 mod search;
 mod error;
 mod bis_c;
 mod ui;
 mod constants;
+mod history;
+mod config;
+mod query;
+mod fold;
+mod scorer;
+mod frecency;
+mod normalize;
+mod index;
+#[cfg(test)]
+mod bench;
 
-fn main() {
-    // init logging
-    match env_logger::init() {
-        Ok(()) => {
-            trace!("Logging initialized successfully");
+// read everything we search before touching the terminal
+fn load(config: Config, histories: Vec<PathBuf>) -> Result<SearchBase, BisError> {
+    let mut base = SearchBase::new(config);
+
+    debug!("Reading history");
+    try!(base.read_histories(&histories));
+    base.read_selections();
+
+    Ok(base)
+}
+
+// print the matches for a query instead of starting the UI
+fn print_matches(config: Config, histories: Vec<PathBuf>, query: String) -> Result<(), BisError> {
+    let mode = if config.regex {
+        SearchMode::Regex
+    } else {
+        SearchMode::Fuzzy
+    };
+    let explain = config.explain;
+    let (k, offset) = (config.matches, config.offset);
+
+    let base = try!(load(config, histories));
+
+    let result = base.query(query, mode, explain, k, offset, &mut Scratch::default());
+
+    match result.status {
+        Some(ref status) => {
+            let _ = writeln!(io::stderr(), "bis: {}", status);
         },
+        None => {
+            trace!("No status to print");
+        }
+    }
+
+    let handle = io::stdout();
+    let mut output = handle.lock();
+
+    for item in result.matches.iter() {
+        match writeln!(output, "{}", item.line()) {
+            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to write match", Some(Box::new(e)))),
+            Ok(_) => {
+                trace!("Wrote match successfully");
+            }
+        }
+
+        if explain {
+            match item.explanation() {
+                Some(explanation) => {
+                    // where each term matched, counted in characters
+                    let positions: Vec<String> = explanation.positions.iter().map(|group| {
+                        let group: Vec<String> = group.iter().map(|pos| {format!("{}", pos)}).collect();
+                        format!(" [{}]", group.join(" "))
+                    }).collect();
+                    match writeln!(output, "  at{} {}", positions.concat(), explanation) {
+                        Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to write explanation", Some(Box::new(e)))),
+                        Ok(_) => {
+                            trace!("Wrote explanation successfully");
+                        }
+                    }
+                },
+                None => {
+                    trace!("No explanation for {:?}", item.line());
+                }
+            }
+        }
+    }
+
+    // say where the rest are, --offset gets the next page
+    let shown = result.offset + result.matches.len();
+    if result.total > shown {
+        let _ = writeln!(io::stderr(), "bis: {} more matches, starting at offset {}", result.total - shown, shown);
+    }
+
+    Ok(())
+}
+
+fn run() -> Result<(), BisError> {
+    debug!("Loading configuration");
+    let config = match Config::load() {
         Err(e) => {
-            panic!("Failed to initialize logging: {}", e);
+            return Err(BisError::wrap("Failed to load configuration", e));
+        },
+        Ok(c) => c
+    };
+
+    if config.help {
+        print!("{}", Config::usage());
+        return Ok(());
+    }
+
+    // find the history before touching the terminal
+    debug!("Finding history");
+    let histories = match history::discover(&config.history) {
+        Err(e) => {
+            return Err(BisError::wrap("Failed to find history", e));
+        },
+        Ok(h) => {
+            trace!("History files: {:?}", h);
+            h
+        }
+    };
+
+    match config.query.clone() {
+        Some(query) => {
+            debug!("Not starting the UI");
+            return print_matches(config, histories, query);
+        },
+        None => {
+            trace!("No query given");
         }
     }
 
     // create the UI instance
     debug!("Creating UI instance");
 
-    let mut ui = match UI::create() {
+    let base = try!(load(config.clone(), histories));
+
+    let mut ui = match UI::create(config, base) {
         Err(e) => {
-            panic!("Failed to create UI instance: {}", e);
+            return Err(BisError::wrap("Failed to create UI instance", e));
         },
         Ok(ui) => {
             trace!("UI instance created successfully");
@@ -63,9 +192,35 @@ fn main() {
     match ui.start() {
         Ok(_) => {
             debug!("UI finished successfully");
+            Ok(())
+        },
+        Err(e) => {
+            Err(BisError::wrap("UI failure", e))
+        }
+    }
+}
+
+fn main() {
+    // init logging
+    match env_logger::init() {
+        Ok(()) => {
+            trace!("Logging initialized successfully");
+        },
+        Err(e) => {
+            let _ = writeln!(io::stderr(), "bis: Failed to initialize logging: {}", e);
+            process::exit(1);
+        }
+    }
+
+    // the UI has been dropped and the terminal restored by the time run returns
+    match run() {
+        Ok(_) => {
+            trace!("Exiting successfully");
         },
         Err(e) => {
-            panic!("UI failure: {}", e);
+            // report the error plainly, the exit code tells scripts what kind it was
+            let _ = writeln!(io::stderr(), "bis: {}", e);
+            process::exit(e.kind().exit_code());
         }
     }
 }
@@ -0,0 +1,226 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// which spellings of a command count as the same command
+//
+// the normalized form is only used to find duplicates, the most recent
+// spelling is what gets shown and inserted. nothing inside quotes or
+// after a backslash is touched unless quotes are canonicalized.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalize {
+    // leading and trailing whitespace
+    pub trim: bool,
+    // runs of whitespace become one space
+    pub whitespace: bool,
+    // trailing semicolons
+    pub semicolon: bool,
+    // 'foo', "foo" and foo are the same
+    pub quotes: bool
+}
+
+impl Default for Normalize {
+    fn default() -> Normalize {
+        Normalize {
+            trim: true,
+            whitespace: true,
+            semicolon: true,
+            quotes: false
+        }
+    }
+}
+
+// characters that never need quoting
+fn is_safe(c: char) -> bool {
+    c.is_alphanumeric() || "_./:=@%+,-".contains(c)
+}
+
+// the index of the quote closing the one at start
+fn closing_quote(chars: &[char], start: usize) -> Option<usize> {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == '\\' && quote == '"' {
+            // double quotes have escapes, single quotes don't
+            i += 2;
+        } else if chars[i] == quote {
+            return Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+// the quoted text in the plainest quoting that means the same thing
+fn canonical_quote(quote: char, content: &str) -> String {
+    if !content.is_empty() && content.chars().all(is_safe) {
+        content.to_owned()
+    } else if quote == '"' && !content.chars().any(|c| {"$`\\!'".contains(c)}) {
+        // nothing in there that double quotes would expand
+        format!("'{}'", content)
+    } else {
+        format!("{}{}{}", quote, content, quote)
+    }
+}
+
+impl Normalize {
+    // the rules named, or None if a name isn't one
+    pub fn from_names<T: AsRef<str>>(names: &[T]) -> Option<Normalize> {
+        let mut result = Normalize {
+            trim: false,
+            whitespace: false,
+            semicolon: false,
+            quotes: false
+        };
+
+        for name in names.iter() {
+            match name.as_ref().trim() {
+                "trim" => result.trim = true,
+                "whitespace" => result.whitespace = true,
+                "semicolon" => result.semicolon = true,
+                "quotes" => result.quotes = true,
+                // turns everything off
+                "none" => {},
+                _ => return None
+            }
+        }
+
+        Some(result)
+    }
+
+    pub fn apply(&self, line: &str) -> String {
+        let chars: Vec<char> = line.chars().collect();
+        let mut result = String::with_capacity(line.len());
+        // whether the line ends inside a quote
+        let mut open = false;
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '\\' {
+                // escapes are kept as they are
+                result.push(c);
+                if i + 1 < chars.len() {
+                    result.push(chars[i + 1]);
+                }
+                i += 2;
+            } else if c == '\'' || c == '"' {
+                match closing_quote(&chars, i) {
+                    Some(end) => {
+                        let content: String = chars[i + 1..end].iter().cloned().collect();
+                        if self.quotes {
+                            result.push_str(&canonical_quote(c, &content));
+                        } else {
+                            result.push(c);
+                            result.push_str(&content);
+                            result.push(c);
+                        }
+                        i = end + 1;
+                    },
+                    None => {
+                        // unterminated, leave the rest alone
+                        result.extend(chars[i..].iter().cloned());
+                        open = true;
+                        break;
+                    }
+                }
+            } else if c.is_whitespace() && self.whitespace {
+                while i < chars.len() && chars[i].is_whitespace() {
+                    i += 1;
+                }
+                result.push(' ');
+            } else {
+                result.push(c);
+                i += 1;
+            }
+        }
+
+        if self.semicolon && !open {
+            // but not find's "\;"
+            loop {
+                let len = result.trim_right().len();
+                if result[..len].ends_with(';') && !result[..len].ends_with("\\;") {
+                    result.truncate(len - 1);
+                } else {
+                    break;
+                }
+            }
+        }
+
+        if self.trim {
+            result = if open {
+                result.trim_left().to_owned()
+            } else {
+                result.trim().to_owned()
+            };
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all() -> Normalize {
+        Normalize {
+            trim: true,
+            whitespace: true,
+            semicolon: true,
+            quotes: true
+        }
+    }
+
+    #[test]
+    fn default_rules() {
+        let normalize = Normalize::default();
+        assert_eq!(normalize.apply("  git   status ;; "), "git status");
+        // quoted text and escapes are left alone
+        assert_eq!(normalize.apply("echo 'a   b'  \"c  d\""), "echo 'a   b' \"c  d\"");
+        assert_eq!(normalize.apply("echo a\\  b"), "echo a\\  b");
+        assert_eq!(normalize.apply("find . -exec rm {} \\;"), "find . -exec rm {} \\;");
+        assert_eq!(normalize.apply("echo 'unterminated   ;"), "echo 'unterminated   ;");
+        // quotes are kept as typed
+        assert_eq!(normalize.apply("cd 'src'"), "cd 'src'");
+    }
+
+    #[test]
+    fn canonical_quotes() {
+        let normalize = all();
+        assert_eq!(normalize.apply("cd 'src'"), "cd src");
+        assert_eq!(normalize.apply("cd \"src\""), "cd src");
+        assert_eq!(normalize.apply("echo \"a b\""), "echo 'a b'");
+        assert_eq!(normalize.apply("echo 'a b'"), "echo 'a b'");
+        // double quotes that expand something have to stay
+        assert_eq!(normalize.apply("echo \"$HOME x\""), "echo \"$HOME x\"");
+        assert_eq!(normalize.apply("echo \"a \\\" b\""), "echo \"a \\\" b\"");
+        assert_eq!(normalize.apply("echo ''"), "echo ''");
+    }
+
+    #[test]
+    fn rules_turn_off() {
+        let normalize = Normalize::from_names(&["none"]).unwrap();
+        assert_eq!(normalize.apply("  ls  ; "), "  ls  ; ");
+
+        let normalize = Normalize::from_names(&["semicolon"]).unwrap();
+        assert_eq!(normalize.apply("  ls  ; "), "  ls  ");
+
+        let normalize = Normalize::from_names(&["trim", "whitespace"]).unwrap();
+        assert_eq!(normalize.apply("  ls  -l ;"), "ls -l ;");
+
+        assert_eq!(Normalize::from_names(&["trim", "bogus"]), None);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// query syntax
+//
+// terms are separated by whitespace and must all match:
+//   term     fuzzy match, each word on its own and in any order
+//   'term    exact substring
+//   ^term    the command starts with term
+//   term$    the command ends with term
+//   ^term$   the command is exactly term
+//   !term    the command does not contain term (also !^term, !term$)
+//   a | b    either a or b matches
+
+use fold::{QueryChar, fold_line, matches_required};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TermKind {
+    Fuzzy,
+    Exact,
+    Prefix,
+    Suffix,
+    Equal
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub kind: TermKind,
+    pub text: String,
+    pub negated: bool
+}
+
+// a line folded for finding terms in, reused from one line to the next
+#[derive(Debug, Default)]
+pub struct Haystack {
+    chars: Vec<char>,
+    origin: Vec<usize>,
+    original: Vec<char>,
+    // the char positions of the last term found
+    pub positions: Vec<usize>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    // every group must match, a group matches if any of its terms does
+    pub groups: Vec<Vec<Term>>,
+    // what was typed
+    pub text: String
+}
+
+impl Term {
+    fn parse(token: &str) -> Term {
+        let mut text = token;
+        let mut negated = false;
+
+        if text.len() > 1 && text.starts_with('!') {
+            negated = true;
+            text = &text[1..];
+        }
+
+        let mut prefix = false;
+        let mut suffix = false;
+        let mut exact = false;
+
+        if text.len() > 1 && text.starts_with('\'') {
+            exact = true;
+            text = &text[1..];
+        } else if text.len() > 1 && text.starts_with('^') {
+            prefix = true;
+            text = &text[1..];
+        }
+
+        if text.len() > 1 && text.ends_with('$') {
+            suffix = true;
+            text = &text[..text.len() - 1];
+        }
+
+        let kind = if prefix && suffix {
+            TermKind::Equal
+        } else if prefix {
+            TermKind::Prefix
+        } else if suffix {
+            TermKind::Suffix
+        } else if exact || negated {
+            // excluding a fuzzy match would exclude nearly everything
+            TermKind::Exact
+        } else {
+            TermKind::Fuzzy
+        };
+
+        Term {
+            kind: kind,
+            text: text.to_owned(),
+            negated: negated
+        }
+    }
+
+    // whether a non-fuzzy term, folded into needle, is in line. its char
+    // positions are left in haystack.positions
+    pub fn find(&self, needle: &[QueryChar], line: &str, haystack: &mut Haystack) -> bool {
+        haystack.positions.clear();
+        fold_line(line, &mut haystack.chars, &mut haystack.origin);
+        let chars = &haystack.chars;
+        let origin = &haystack.origin;
+        let n = needle.len();
+
+        // only look at the original characters if we have to
+        haystack.original.clear();
+        if needle.iter().any(|&(_, required)| {required.is_some()}) {
+            haystack.original.extend(line.chars());
+        }
+        let original = &haystack.original;
+
+        // anchors ignore surrounding whitespace
+        let start = chars.iter().position(|c| {!c.is_whitespace()}).unwrap_or(chars.len());
+        let end = chars.iter().rposition(|c| {!c.is_whitespace()}).map(|i| {i + 1}).unwrap_or(start);
+
+        if n > end - start {
+            return false;
+        }
+
+        let matches_at = |i: usize| {
+            needle.iter().enumerate().all(|(j, &(c, required))| {
+                chars[i + j] == c && match required {
+                    None => true,
+                    Some(r) => matches_required(original[origin[i + j]], r)
+                }
+            })
+        };
+
+        let found = match self.kind {
+            TermKind::Prefix => {
+                if matches_at(start) {
+                    Some(start)
+                } else {
+                    None
+                }
+            },
+            TermKind::Suffix => {
+                if matches_at(end - n) {
+                    Some(end - n)
+                } else {
+                    None
+                }
+            },
+            TermKind::Equal => {
+                if end - start == n && matches_at(start) {
+                    Some(start)
+                } else {
+                    None
+                }
+            },
+            TermKind::Exact | TermKind::Fuzzy => {
+                (start..end - n + 1).find(|i| {matches_at(*i)})
+            }
+        };
+
+        // report positions in the original line
+        match found {
+            Some(first) => {
+                haystack.positions.extend(origin[first..first + n].iter().cloned());
+                haystack.positions.dedup();
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl Query {
+    pub fn parse<T: AsRef<str>>(query: T) -> Query {
+        let mut groups: Vec<Vec<Term>> = vec![];
+        let mut join = false;
+
+        for token in query.as_ref().split_whitespace() {
+            if token == "|" {
+                // the next term joins the last group
+                join = !groups.is_empty();
+                continue;
+            }
+
+            let term = Term::parse(token);
+            if join {
+                match groups.last_mut() {
+                    Some(group) => group.push(term),
+                    None => unreachable!()
+                }
+                join = false;
+            } else {
+                groups.push(vec![term]);
+            }
+        }
+
+        trace!("Parsed query: {:?}", groups);
+
+        Query {
+            groups: groups,
+            text: query.as_ref().to_owned()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fold::{CaseMode, fold_query};
+
+    fn term(kind: TermKind, text: &str, negated: bool) -> Term {
+        Term {
+            kind: kind,
+            text: text.to_owned(),
+            negated: negated
+        }
+    }
+
+    fn find(kind: TermKind, text: &str, line: &str) -> Option<Vec<usize>> {
+        let mut haystack = Haystack::default();
+        let needle = fold_query(text, CaseMode::Smart);
+        if term(kind, text, false).find(&needle, line, &mut haystack) {
+            Some(haystack.positions.clone())
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn parse_terms() {
+        let query = Query::parse("git 'stash ^cd make$ ^ls$ !rm !^sudo");
+        assert_eq!(query.groups, vec![vec![term(TermKind::Fuzzy, "git", false)],
+                                      vec![term(TermKind::Exact, "stash", false)],
+                                      vec![term(TermKind::Prefix, "cd", false)],
+                                      vec![term(TermKind::Suffix, "make", false)],
+                                      vec![term(TermKind::Equal, "ls", false)],
+                                      vec![term(TermKind::Exact, "rm", true)],
+                                      vec![term(TermKind::Prefix, "sudo", true)]]);
+    }
+
+    #[test]
+    fn parse_alternatives() {
+        let query = Query::parse("ssh | scp host");
+        assert_eq!(query.groups, vec![vec![term(TermKind::Fuzzy, "ssh", false), term(TermKind::Fuzzy, "scp", false)],
+                                      vec![term(TermKind::Fuzzy, "host", false)]]);
+
+        // a leading or trailing bar has nothing to join
+        let query = Query::parse("| ls |");
+        assert_eq!(query.groups, vec![vec![term(TermKind::Fuzzy, "ls", false)]]);
+    }
+
+    #[test]
+    fn parse_bare_symbols() {
+        let query = Query::parse("! ^ $ '");
+        assert_eq!(query.groups, vec![vec![term(TermKind::Fuzzy, "!", false)],
+                                      vec![term(TermKind::Fuzzy, "^", false)],
+                                      vec![term(TermKind::Fuzzy, "$", false)],
+                                      vec![term(TermKind::Fuzzy, "'", false)]]);
+        assert!(Query::parse("  ").is_empty());
+    }
+
+    #[test]
+    fn find_anchored_terms() {
+        assert_eq!(find(TermKind::Exact, "sta", "git stash"), Some(vec![4, 5, 6]));
+        assert_eq!(find(TermKind::Prefix, "git", "  git stash"), Some(vec![2, 3, 4]));
+        assert_eq!(find(TermKind::Prefix, "stash", "git stash"), None);
+        assert_eq!(find(TermKind::Suffix, "stash", "git stash "), Some(vec![4, 5, 6, 7, 8]));
+        assert_eq!(find(TermKind::Suffix, "git", "git stash"), None);
+        assert_eq!(find(TermKind::Equal, "ls", " ls "), Some(vec![1, 2]));
+        assert_eq!(find(TermKind::Equal, "ls", "ls -l"), None);
+        assert_eq!(find(TermKind::Exact, "longer than the line", "ls"), None);
+    }
+
+    #[test]
+    fn find_folds_case_and_accents() {
+        assert_eq!(find(TermKind::Exact, "cafe", "echo Café"), Some(vec![5, 6, 7, 8]));
+        // a decomposed accent maps back to its base letter
+        assert_eq!(find(TermKind::Suffix, "e", "cafe\u{301}"), Some(vec![3]));
+        assert_eq!(find(TermKind::Exact, "strasse", "Straße"), Some(vec![0, 1, 2, 3, 4, 5]));
+        // uppercase query characters only match themselves
+        assert_eq!(find(TermKind::Exact, "Make", "make"), None);
+        assert_eq!(find(TermKind::Exact, "Make", "Make"), Some(vec![0, 1, 2, 3]));
+    }
+}
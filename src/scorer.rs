@@ -0,0 +1,1039 @@
+// Copyright 2015 Jerome Rasky <jerome@rasky.co>
+//
+// Licensed under the Apache License, version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     <http://www.apache.org/licenses/LICENSE-2.0>
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
+// License for the specific language concerning governing permissions and
+// limitations under the License.
+
+// scoring algorithms
+//
+// a scorer gives every character of a line some heat when the line is
+// indexed, and scores the positions a query matched at. positions are in
+// the folded line, heat has one entry per folded character.
+
+use std::fmt::Debug;
+use std::cmp;
+use std::iter;
+
+use config::Config;
+use fold::is_combining;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScorerKind {
+    // ours
+    Bis,
+    // emacs flx
+    Flx,
+    // fzf's v2 algorithm
+    Fzf
+}
+
+pub trait Scorer: Debug {
+    // the heat of each original character of line
+    fn heatmap(&self, line: &[char], config: &Config) -> Vec<isize>;
+
+    // the score of a match at positions, which are increasing
+    fn score(&self, heat: &[isize], positions: &[usize], config: &Config) -> isize;
+
+    // the named parts of the score of a match at positions, they add up to it
+    fn explain(&self, heat: &[isize], positions: &[usize], config: &Config) -> Vec<(&'static str, isize)> {
+        vec![("score", self.score(heat, positions, config))]
+    }
+
+    // the score of the best match given the candidate positions of each
+    // query character, which is left in alignment.positions
+    fn align(&self, heat: &[isize], candidates: &[Vec<usize>], config: &Config,
+             alignment: &mut Alignment) -> Option<isize>;
+}
+
+// buffers kept between alignments, so aligning doesn't allocate once
+// they're big enough
+#[derive(Debug, Default)]
+pub struct Alignment {
+    // the best positions found
+    pub positions: Vec<usize>,
+    // where each query character's part of score starts, and the best
+    // score of any candidate from some point on in a row
+    rows: Vec<usize>,
+    suffix: Vec<Option<isize>>,
+    score: Vec<Option<isize>>,
+    // for fzf, the bonuses a run can carry and the state each score came from
+    levels: Vec<isize>,
+    from: Vec<usize>
+}
+
+#[derive(Debug)]
+pub struct BisScorer;
+
+#[derive(Debug)]
+pub struct FlxScorer;
+
+#[derive(Debug)]
+pub struct FzfScorer;
+
+#[derive(PartialEq)]
+enum CharClass {
+    Whitespace,
+    Numeric,
+    Alphabetic,
+    First,
+    Other
+}
+
+impl ScorerKind {
+    pub fn from_name<T: AsRef<str>>(name: T) -> Option<ScorerKind> {
+        match name.as_ref() {
+            "bis" => Some(ScorerKind::Bis),
+            "flx" => Some(ScorerKind::Flx),
+            "fzf" => Some(ScorerKind::Fzf),
+            _ => None
+        }
+    }
+
+    pub fn create(&self) -> Box<Scorer + Send> {
+        match *self {
+            ScorerKind::Bis => Box::new(BisScorer),
+            ScorerKind::Flx => Box::new(FlxScorer),
+            ScorerKind::Fzf => Box::new(FzfScorer)
+        }
+    }
+}
+
+// the best strictly increasing choice of one candidate per query
+// character, for scores that add up a value for each position, a bonus for
+// each position continuing a run of adjacent ones given how long the run is
+// so far, capped at longest, and a value for where the match starts and
+// ends. ties go to the first combination in order, like trying every one
+// would
+fn align_runs(candidates: &[Vec<usize>], longest: usize, alignment: &mut Alignment,
+              position: &Fn(usize) -> isize, run: &Fn(usize) -> isize,
+              ends: &Fn(usize, usize) -> isize) -> Option<isize> {
+    alignment.positions.clear();
+    if candidates.is_empty() || candidates.iter().any(|list| {list.is_empty()}) {
+        return None;
+    }
+
+    let states = longest + 1;
+    alignment.rows.clear();
+    let mut cells = 0;
+    for list in candidates.iter() {
+        alignment.rows.push(cells);
+        cells += list.len();
+    }
+    alignment.score.clear();
+    alignment.score.extend(iter::repeat(None).take(cells * states));
+    alignment.suffix.clear();
+    alignment.suffix.extend(iter::repeat(None).take(cells));
+
+    // the end only depends on the start, so try each start on its own
+    let mut best: Option<(isize, usize)> = None;
+    for first in 0..candidates[0].len() {
+        let score = finish_runs(candidates, states, alignment, first, position, run, ends);
+        match (score, best) {
+            (Some(score), Some((last, _))) if score <= last => (),
+            (Some(score), _) => best = Some((score, first)),
+            (None, _) => ()
+        }
+    }
+
+    let (best, first) = match best {
+        Some(best) => best,
+        None => return None
+    };
+
+    // fill the table in for the best start again and follow it forward,
+    // taking the first candidate that keeps the best score each time
+    finish_runs(candidates, states, alignment, first, position, run, ends);
+    let mut idx = first;
+    let mut pos = candidates[0][first];
+    let mut state = 0;
+    alignment.positions.push(pos);
+    for (row, list) in candidates.iter().enumerate().skip(1) {
+        let here = alignment.score[(alignment.rows[row - 1] + idx) * states + state];
+        let bonus = if state > 0 {
+            run(state)
+        } else {
+            0
+        };
+        let wanted = here.map(|score| {score - position(pos) - bonus});
+
+        let start = match list.binary_search(&(pos + 1)) {
+            Ok(start) | Err(start) => start
+        };
+        let mut found = None;
+        for (next, &next_pos) in list.iter().enumerate().skip(start) {
+            let next_state = if next_pos == pos + 1 {
+                cmp::min(state + 1, longest)
+            } else {
+                0
+            };
+            if alignment.score[(alignment.rows[row] + next) * states + next_state] == wanted {
+                found = Some((next, next_state));
+                break;
+            }
+        }
+
+        match found {
+            Some((next, next_state)) => {
+                idx = next;
+                pos = list[next];
+                state = next_state;
+                alignment.positions.push(pos);
+            },
+            // can't happen, the table led here
+            None => return None
+        }
+    }
+
+    Some(best)
+}
+
+// fill in the best score for finishing a match from each candidate, given
+// how long the run ending there is, when the match starts at first. gives
+// the best score starting there
+fn finish_runs(candidates: &[Vec<usize>], states: usize, alignment: &mut Alignment, first: usize,
+               position: &Fn(usize) -> isize, run: &Fn(usize) -> isize,
+               ends: &Fn(usize, usize) -> isize) -> Option<isize> {
+    let start = candidates[0][first];
+    let last_row = candidates.len() - 1;
+
+    for (row, list) in candidates.iter().enumerate().rev() {
+        let offset = alignment.rows[row];
+
+        for (idx, &pos) in list.iter().enumerate() {
+            for state in 0..states {
+                let cell = (offset + idx) * states + state;
+                if row == 0 && (idx != first || state > 0) {
+                    alignment.score[cell] = None;
+                    continue;
+                }
+
+                let bonus = if state > 0 {
+                    run(state)
+                } else {
+                    0
+                };
+                let rest = if row == last_row {
+                    // a last candidate before the start is never reached
+                    if row > 0 && pos <= start {
+                        None
+                    } else {
+                        Some(ends(start, pos))
+                    }
+                } else {
+                    // the best of the next character right after this one,
+                    // or anywhere later starting a new run
+                    let next = &candidates[row + 1];
+                    let next_offset = alignment.rows[row + 1];
+                    let mut later = match next.binary_search(&(pos + 1)) {
+                        Ok(later) | Err(later) => later
+                    };
+                    let mut rest = None;
+                    if later < next.len() && next[later] == pos + 1 {
+                        let next_state = cmp::min(state + 1, states - 1);
+                        rest = alignment.score[(next_offset + later) * states + next_state];
+                        later += 1;
+                    }
+                    if later < next.len() {
+                        rest = cmp::max(rest, alignment.suffix[next_offset + later]);
+                    }
+                    rest
+                };
+
+                alignment.score[cell] = rest.map(|rest| {rest + position(pos) + bonus});
+            }
+        }
+
+        // the best score from each candidate on without a run
+        let mut best = None;
+        for idx in (0..list.len()).rev() {
+            best = cmp::max(best, alignment.score[(offset + idx) * states]);
+            alignment.suffix[offset + idx] = best;
+        }
+    }
+
+    alignment.score[(alignment.rows[0] + first) * states]
+}
+
+// characters that end one command and start another
+fn is_command_separator(c: char) -> bool {
+    match c {
+        '|' | '&' | ';' | '`' => true,
+        _ => false
+    }
+}
+
+impl Scorer for BisScorer {
+    fn heatmap(&self, line: &[char], config: &Config) -> Vec<isize> {
+        let mut heat = Vec::with_capacity(line.len());
+
+        let mut ws_score = 0;
+        let mut cs_score = 0;
+        let mut cur_class = CharClass::First;
+        // character class changes don't stack
+        let mut cs_change = false;
+        // shell syntax state
+        let mut prev: Option<char> = None;
+        let mut new_command = false;
+        let mut dashes = false;
+
+        for &c in line.iter() {
+            // combining marks belong to the previous character and fold away
+            if is_combining(c) {
+                heat.push(0);
+                continue;
+            }
+
+            // don't map whitespace
+            if !c.is_whitespace() {
+                // update the character class change score if needed
+                if cur_class == CharClass::First {
+                    // add the first character factor on top of class change
+                    cs_score += config.first_factor;
+                }
+                if c.is_numeric() {
+                    if cur_class != CharClass::Numeric {
+                        cur_class = CharClass::Numeric;
+                        if !cs_change {
+                            cs_score += config.class_factor;
+                            cs_change = true;
+                        }
+                    } else {
+                        cs_change = false;
+                    }
+                } else if c.is_alphabetic() {
+                    if cur_class != CharClass::Alphabetic {
+                        cur_class = CharClass::Alphabetic;
+                        if !cs_change {
+                            cs_score += config.class_factor;
+                            cs_change = true;
+                        }
+                    } else {
+                        cs_change = false;
+                    }
+                } else {
+                    if cur_class != CharClass::Other {
+                        cur_class = CharClass::Other;
+                        if !cs_change {
+                            cs_score += config.class_factor;
+                            cs_change = true;
+                        }
+                    } else {
+                        cs_change = false;
+                    }
+                }
+
+            } else {
+                // whitespace is treated differently
+                cur_class = CharClass::Whitespace;
+                ws_score = config.whitespace_factor;
+            }
+
+            // shell syntax boundaries only heat the character they're in front of
+            let syntax_score = if c.is_whitespace() {
+                0
+            } else if new_command && !is_command_separator(c) {
+                // the first word after |, &&, ; or $(
+                new_command = false;
+                config.command_factor
+            } else if dashes && c != '-' {
+                config.flag_factor
+            } else if prev == Some('/') && c != '/' {
+                config.path_factor
+            } else if prev == Some('=') && c != '=' {
+                config.assign_factor
+            } else {
+                0
+            };
+
+            // words inside identifiers, a class change alone misses these
+            // since class changes don't stack
+            let word_score = match prev {
+                _ if syntax_score != 0 => 0,
+                Some(p) if p.is_lowercase() && c.is_uppercase() => config.word_factor,
+                Some(p) if (p == '_' || p == '-') && c.is_alphanumeric() => config.word_factor,
+                _ => 0
+            };
+
+            if is_command_separator(c) || (c == '(' && prev == Some('$')) {
+                new_command = true;
+            }
+            // flags start with dashes after whitespace
+            dashes = c == '-' && (dashes || prev.map(|p| {p.is_whitespace()}).unwrap_or(true));
+
+            // push to the heatmap
+            heat.push(ws_score + cs_score + syntax_score + word_score);
+
+            prev = Some(c);
+
+            // reduce things
+            ws_score /= config.whitespace_reduce;
+            if !cs_change {
+                cs_score /= config.class_reduce;
+            }
+        }
+
+        heat
+    }
+
+    fn score(&self, heat: &[isize], pgroup: &[usize], config: &Config) -> isize {
+        self.parts(heat, pgroup, config).iter().map(|&(_, value)| {value}).sum()
+    }
+
+    fn explain(&self, heat: &[isize], pgroup: &[usize], config: &Config) -> Vec<(&'static str, isize)> {
+        self.parts(heat, pgroup, config).to_vec()
+    }
+
+    fn align(&self, heat: &[isize], candidates: &[Vec<usize>], config: &Config,
+             alignment: &mut Alignment) -> Option<isize> {
+        // the distances between positions add up to the last minus the
+        // first, so dist and lead only depend on the ends
+        let gaps = cmp::max(candidates.len(), 2) as isize - 1;
+        align_runs(candidates, 1, alignment,
+                   &|pos| {heat.get(pos).cloned().unwrap_or(0) * config.heat_weight},
+                   &|_| {config.run_weight},
+                   &|first, last| {
+                       ((last - first) as isize / gaps) * config.dist_weight +
+                           first as isize * config.lead_weight
+                   })
+    }
+}
+
+impl BisScorer {
+    fn parts(&self, heat: &[isize], pgroup: &[usize], config: &Config) -> [(&'static str, isize); 4] {
+        // find the average distance between the indexes
+        let mut dist_total = 0;
+        let mut dist_count = 0;
+        // count the characters matched right after the previous one
+        let mut runs = 0;
+        for i in 1..pgroup.len() {
+            dist_total += (pgroup[i] - pgroup[i - 1]) as isize;
+            dist_count += 1;
+            if pgroup[i] == pgroup[i - 1] + 1 {
+                runs += 1;
+            }
+        }
+        // avoid division by zero
+        if dist_count == 0 {
+            dist_count = 1;
+        }
+        // sum the heatmap, a trailing combining mark has no heat
+        let heat_sum: isize = pgroup.iter().map(|pos| {heat.get(*pos).cloned().unwrap_or(0)}).sum();
+        // how far into the line the match starts
+        let lead = pgroup.first().cloned().unwrap_or(0) as isize;
+        [("dist", (dist_total / dist_count) * config.dist_weight),
+         ("heat", heat_sum * config.heat_weight),
+         ("run", runs * config.run_weight),
+         ("lead", lead * config.lead_weight)]
+    }
+}
+
+// flx.el's notion of words
+fn flx_word(c: Option<char>) -> bool {
+    match c {
+        None | Some(' ') | Some('-') | Some('_') | Some(':') | Some('.') | Some('/') | Some('\\') => false,
+        Some(_) => true
+    }
+}
+
+// flx counts digits as capitals, they're their own upcase
+fn flx_capital(c: Option<char>) -> bool {
+    flx_word(c) && match c {
+        Some(c) => !c.is_lowercase(),
+        None => false
+    }
+}
+
+fn flx_boundary(last: Option<char>, c: Option<char>) -> bool {
+    last.is_none() ||
+        (!flx_capital(last) && flx_capital(c)) ||
+        (!flx_word(last) && flx_word(c))
+}
+
+// a port of flx-get-heatmap-str and flx-score, treating the whole
+// command as one group the way flx does for anything that isn't a file
+impl Scorer for FlxScorer {
+    fn heatmap(&self, line: &[char], _: &Config) -> Vec<isize> {
+        let len = line.len();
+        // base
+        let mut heat = vec![-35; len];
+        if len == 0 {
+            return heat;
+        }
+        // final char bonus
+        heat[len - 1] += 1;
+
+        let mut words = vec![];
+        let mut word_count = 0;
+        let mut last: Option<char> = None;
+
+        for (idx, &c) in line.iter().enumerate() {
+            // before we find any words, all separators are words of length 1
+            let effective_last = if word_count == 0 {
+                None
+            } else {
+                last
+            };
+            if flx_boundary(effective_last, Some(c)) {
+                words.push(idx);
+            }
+            if !flx_word(last) && flx_word(Some(c)) {
+                word_count += 1;
+            }
+            // penalize extensions
+            if last == Some('.') {
+                heat[idx] -= 45;
+            }
+            last = Some(c);
+        }
+
+        // the only group is the basepath if it has any words
+        let group_score = if words.is_empty() {
+            -3
+        } else {
+            35 - word_count
+        };
+        for h in heat.iter_mut() {
+            *h += group_score;
+        }
+
+        // later words and later characters in a word are worth less
+        let mut last_word = len;
+        for (word_index, &word) in words.iter().enumerate().rev() {
+            // beginning of word bonus
+            heat[word] += 85;
+            for (char_index, idx) in (word..last_word).enumerate() {
+                heat[idx] -= 3 * word_index as isize + char_index as isize;
+            }
+            last_word = word;
+        }
+
+        heat
+    }
+
+    fn score(&self, heat: &[isize], positions: &[usize], _: &Config) -> isize {
+        self.parts(heat, positions).iter().map(|&(_, value)| {value}).sum()
+    }
+
+    fn explain(&self, heat: &[isize], positions: &[usize], _: &Config) -> Vec<(&'static str, isize)> {
+        self.parts(heat, positions).to_vec()
+    }
+
+    fn align(&self, heat: &[isize], candidates: &[Vec<usize>], _: &Config,
+             alignment: &mut Alignment) -> Option<isize> {
+        let count = candidates.len();
+        let whole = if count > 1 && count < 5 && count == heat.len() {
+            10000
+        } else {
+            0
+        };
+        align_runs(candidates, 4, alignment,
+                   &|pos| {heat.get(pos).cloned().unwrap_or(0)},
+                   &|contiguous| {45 + 15 * contiguous as isize},
+                   &|_, _| {whole})
+    }
+}
+
+impl FlxScorer {
+    fn parts(&self, heat: &[isize], positions: &[usize]) -> [(&'static str, isize); 3] {
+        // short queries matching the whole line win outright
+        let whole = if positions.len() > 1 && positions.len() < 5 && positions.len() == heat.len() {
+            10000
+        } else {
+            0
+        };
+
+        let mut heat_sum = 0;
+        let mut run_sum = 0;
+        let mut contiguous = 0;
+        let mut last: Option<usize> = None;
+        for &pos in positions.iter() {
+            if last.map(|l| {l + 1 == pos}).unwrap_or(false) {
+                contiguous += 1;
+            } else {
+                contiguous = 0;
+            }
+            heat_sum += heat.get(pos).cloned().unwrap_or(0);
+            if contiguous > 0 {
+                run_sum += 45 + 15 * cmp::min(contiguous, 4);
+            }
+            last = Some(pos);
+        }
+
+        [("whole", whole), ("heat", heat_sum), ("run", run_sum)]
+    }
+}
+
+// fzf's constants, from algo.go
+const FZF_MATCH: isize = 16;
+const FZF_GAP_START: isize = -3;
+const FZF_GAP_EXTENSION: isize = -1;
+const FZF_BOUNDARY: isize = FZF_MATCH / 2;
+const FZF_NON_WORD: isize = FZF_MATCH / 2;
+const FZF_CAMEL_123: isize = FZF_BOUNDARY + FZF_GAP_EXTENSION;
+const FZF_CONSECUTIVE: isize = -(FZF_GAP_START + FZF_GAP_EXTENSION);
+const FZF_FIRST_CHAR_MULTIPLIER: isize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FzfClass {
+    NonWord,
+    Lower,
+    Upper,
+    Letter,
+    Number
+}
+
+fn fzf_class(c: char) -> FzfClass {
+    if c.is_lowercase() {
+        FzfClass::Lower
+    } else if c.is_uppercase() {
+        FzfClass::Upper
+    } else if c.is_numeric() {
+        FzfClass::Number
+    } else if c.is_alphabetic() {
+        FzfClass::Letter
+    } else {
+        FzfClass::NonWord
+    }
+}
+
+// set a cell's score if it's better than what's there, remembering where
+// it came from
+fn keep_best(score: &mut [Option<isize>], from: &mut [usize], cell: usize, value: isize, origin: usize) {
+    match score[cell] {
+        Some(current) if current >= value => (),
+        _ => {
+            score[cell] = Some(value);
+            from[cell] = origin;
+        }
+    }
+}
+
+// the bonus a match at pos gets, given how far into a run of matches it is
+fn fzf_bonus(heat: &[isize], pos: usize, consecutive: usize, first_bonus: isize) -> isize {
+    let bonus = heat.get(pos).cloned().unwrap_or(0);
+    if consecutive == 0 || bonus == FZF_BOUNDARY {
+        bonus
+    } else {
+        cmp::max(bonus, cmp::max(first_bonus, FZF_CONSECUTIVE))
+    }
+}
+
+// fzf's FuzzyMatchV2 scoring, aligned over the candidate positions
+// instead of comparing characters. every query character has to match, so
+// there is no restarting at zero
+impl Scorer for FzfScorer {
+    // fzf's heat is its per character bonus
+    fn heatmap(&self, line: &[char], _: &Config) -> Vec<isize> {
+        let mut prev = FzfClass::NonWord;
+        line.iter().map(|&c| {
+            let class = fzf_class(c);
+            let bonus = if prev == FzfClass::NonWord && class != FzfClass::NonWord {
+                FZF_BOUNDARY
+            } else if (prev == FzfClass::Lower && class == FzfClass::Upper) ||
+                (prev != FzfClass::Number && class == FzfClass::Number) {
+                FZF_CAMEL_123
+            } else if class == FzfClass::NonWord {
+                FZF_NON_WORD
+            } else {
+                0
+            };
+            prev = class;
+            bonus
+        }).collect()
+    }
+
+    fn score(&self, heat: &[isize], positions: &[usize], _: &Config) -> isize {
+        self.parts(heat, positions).iter().map(|&(_, value)| {value}).sum()
+    }
+
+    fn explain(&self, heat: &[isize], positions: &[usize], _: &Config) -> Vec<(&'static str, isize)> {
+        self.parts(heat, positions).to_vec()
+    }
+
+    fn align(&self, heat: &[isize], candidates: &[Vec<usize>], _: &Config,
+             alignment: &mut Alignment) -> Option<isize> {
+        alignment.positions.clear();
+        if candidates.is_empty() || candidates.iter().any(|list| {list.is_empty()}) {
+            return None;
+        }
+
+        // a run's first bonus only matters once it's above the consecutive
+        // bonus, so keep a score for each of those a candidate can carry.
+        // fzf keeps one score per cell, which can drop a run that would
+        // have scored more later
+        let levels = &mut alignment.levels;
+        levels.clear();
+        for list in candidates.iter() {
+            for &pos in list.iter() {
+                let level = cmp::max(heat.get(pos).cloned().unwrap_or(0), FZF_CONSECUTIVE);
+                if !levels.contains(&level) {
+                    levels.push(level);
+                }
+            }
+        }
+        let states = levels.len();
+        let level_of = |bonus: isize| {
+            let level = cmp::max(bonus, FZF_CONSECUTIVE);
+            levels.iter().position(|l| {*l == level}).unwrap_or(0)
+        };
+
+        alignment.rows.clear();
+        let mut cells = 0;
+        for list in candidates.iter() {
+            alignment.rows.push(cells);
+            cells += list.len() * states;
+        }
+        alignment.score.clear();
+        alignment.score.extend(iter::repeat(None).take(cells));
+        alignment.from.clear();
+        alignment.from.extend(iter::repeat(0).take(cells));
+        let rows = &alignment.rows;
+        let score = &mut alignment.score;
+        let from = &mut alignment.from;
+
+        for (idx, &pos) in candidates[0].iter().enumerate() {
+            let bonus = heat.get(pos).cloned().unwrap_or(0);
+            score[idx * states + level_of(bonus)] = Some(FZF_MATCH + bonus * FZF_FIRST_CHAR_MULTIPLIER);
+        }
+
+        for row in 1..candidates.len() {
+            let prev = &candidates[row - 1];
+            let prev_offset = rows[row - 1];
+            let offset = rows[row];
+            // the best score of the previous row at least two columns back,
+            // counting the gap from it as if it ended at column zero
+            let mut gapped: Option<(isize, usize)> = None;
+            let mut earlier = 0;
+
+            for (idx, &pos) in candidates[row].iter().enumerate() {
+                while earlier < prev.len() && prev[earlier] + 1 < pos {
+                    for state in 0..states {
+                        let cell = prev_offset + earlier * states + state;
+                        if let Some(s) = score[cell] {
+                            let s = s - prev[earlier] as isize * FZF_GAP_EXTENSION;
+                            match gapped {
+                                Some((best, _)) if best >= s => (),
+                                _ => gapped = Some((s, cell))
+                            }
+                        }
+                    }
+                    earlier += 1;
+                }
+
+                let raw = heat.get(pos).cloned().unwrap_or(0);
+                let here = offset + idx * states;
+
+                // a new run after a gap
+                if let Some((s, origin)) = gapped {
+                    let gap = FZF_GAP_START + (pos as isize - 2) * FZF_GAP_EXTENSION;
+                    keep_best(score, from, here + level_of(raw), s + gap + FZF_MATCH + raw, origin);
+                }
+
+                // right after the previous character, a new word starts over
+                if earlier < prev.len() && prev[earlier] + 1 == pos {
+                    for state in 0..states {
+                        let cell = prev_offset + earlier * states + state;
+                        if let Some(s) = score[cell] {
+                            if raw == FZF_BOUNDARY {
+                                keep_best(score, from, here + level_of(raw), s + FZF_MATCH + raw, cell);
+                            } else {
+                                let bonus = cmp::max(raw, levels[state]);
+                                keep_best(score, from, here + state, s + FZF_MATCH + bonus, cell);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // the best state in the last row, then follow where each came from
+        let last = candidates.len() - 1;
+        let mut best: Option<(isize, usize)> = None;
+        for cell in rows[last]..cells {
+            match (score[cell], best) {
+                (Some(s), Some((b, _))) if s <= b => (),
+                (Some(s), _) => best = Some((s, cell)),
+                (None, _) => ()
+            }
+        }
+        let (best, mut cell) = match best {
+            Some(best) => best,
+            None => return None
+        };
+
+        let positions = &mut alignment.positions;
+        positions.extend(iter::repeat(0).take(candidates.len()));
+        for row in (0..candidates.len()).rev() {
+            positions[row] = candidates[row][(cell - rows[row]) / states];
+            cell = from[cell];
+        }
+
+        Some(best)
+    }
+}
+
+impl FzfScorer {
+    // fzf's calculateScore
+    fn parts(&self, heat: &[isize], positions: &[usize]) -> [(&'static str, isize); 3] {
+        let (first, last) = match (positions.first(), positions.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return [("match", 0), ("gap", 0), ("bonus", 0)]
+        };
+
+        let mut match_sum = 0;
+        let mut gap_sum = 0;
+        let mut bonus_sum = 0;
+        let mut in_gap = false;
+        let mut consecutive = 0;
+        let mut first_bonus = 0;
+        let mut next = positions.iter().peekable();
+
+        for pos in first..last + 1 {
+            if next.peek() == Some(&&pos) {
+                // runs remember the bonus they started with, a new word starts over
+                let raw = heat.get(pos).cloned().unwrap_or(0);
+                if consecutive == 0 || raw == FZF_BOUNDARY {
+                    first_bonus = raw;
+                }
+                let bonus = fzf_bonus(heat, pos, consecutive, first_bonus);
+                match_sum += FZF_MATCH;
+                if pos == first {
+                    bonus_sum += bonus * FZF_FIRST_CHAR_MULTIPLIER;
+                } else {
+                    bonus_sum += bonus;
+                }
+                in_gap = false;
+                consecutive += 1;
+                next.next();
+            } else {
+                gap_sum += if in_gap {
+                    FZF_GAP_EXTENSION
+                } else {
+                    FZF_GAP_START
+                };
+                in_gap = true;
+                consecutive = 0;
+                first_bonus = 0;
+            }
+        }
+
+        [("match", match_sum), ("gap", gap_sum), ("bonus", bonus_sum)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    // the first of the best scoring of every increasing combination
+    fn every_combination(scorer: &Scorer, heat: &[isize], candidates: &[Vec<usize>], config: &Config,
+                         current: &mut Vec<usize>, best: &mut Option<(isize, Vec<usize>)>) {
+        if current.len() == candidates.len() {
+            let score = scorer.score(heat, current, config);
+            let better = match *best {
+                None => true,
+                Some((last, _)) => score > last
+            };
+            if better {
+                *best = Some((score, current.clone()));
+            }
+            return;
+        }
+
+        for &pos in candidates[current.len()].iter() {
+            if current.last().map(|last| {pos > *last}).unwrap_or(true) {
+                current.push(pos);
+                every_combination(scorer, heat, candidates, config, current, best);
+                current.pop();
+            }
+        }
+    }
+
+    #[test]
+    fn align_matches_every_combination() {
+        let config = Config::default();
+        let mut seed: u32 = 7;
+        let mut next = |limit: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % limit
+        };
+
+        let scorers: Vec<Box<Scorer>> = vec![Box::new(BisScorer), Box::new(FlxScorer)];
+        let mut alignment = Alignment::default();
+        for _ in 0..2000 {
+            // a short line over a few characters, so runs and repeats are common
+            let len = 1 + next(14);
+            let line: Vec<usize> = (0..len).map(|_| {next(3)}).collect();
+            let heat: Vec<isize> = (0..len).map(|_| {next(30) as isize - 10}).collect();
+            let query: Vec<usize> = (0..1 + next(5)).map(|_| {next(3)}).collect();
+            let candidates: Vec<Vec<usize>> = query.iter().map(|q| {
+                (0..len).filter(|pos| {line[*pos] == *q}).collect()
+            }).collect();
+
+            for scorer in scorers.iter() {
+                let mut best = None;
+                every_combination(&**scorer, &heat, &candidates, &config, &mut vec![], &mut best);
+                let score = scorer.align(&heat, &candidates, &config, &mut alignment);
+                match best {
+                    Some((best, positions)) => {
+                        assert_eq!(score, Some(best));
+                        assert_eq!(alignment.positions, positions);
+                    },
+                    None => assert_eq!(score, None)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fzf_align_matches_every_combination() {
+        let config = Config::default();
+        let mut seed: u32 = 11;
+        let mut next = |limit: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % limit
+        };
+
+        // fzf's heat only comes from character classes, so build real lines
+        let alphabet = ['a', 'b', 'A', 'B', '1', '-', '/'];
+        let mut alignment = Alignment::default();
+        for _ in 0..2000 {
+            let len = 1 + next(14);
+            let line: Vec<char> = (0..len).map(|_| {alphabet[next(alphabet.len())]}).collect();
+            let heat = FzfScorer.heatmap(&line, &config);
+            // case insensitive, like the folded index
+            let query: Vec<char> = (0..1 + next(5)).map(|_| {alphabet[next(4)].to_ascii_lowercase()}).collect();
+            let candidates: Vec<Vec<usize>> = query.iter().map(|q| {
+                (0..len).filter(|pos| {line[*pos].to_ascii_lowercase() == *q}).collect()
+            }).collect();
+
+            let mut best = None;
+            every_combination(&FzfScorer, &heat, &candidates, &config, &mut vec![], &mut best);
+            let score = FzfScorer.align(&heat, &candidates, &config, &mut alignment);
+            match best {
+                Some((best, _)) => {
+                    assert_eq!(score, Some(FzfScorer.score(&heat, &alignment.positions, &config)));
+                    assert_eq!(score, Some(best));
+                },
+                None => assert_eq!(score, None)
+            }
+        }
+    }
+
+    // the best fzf alignment of query in line, and its positions
+    fn fzf_align(line: &str, query: &str) -> (Option<isize>, Vec<usize>) {
+        let config = Config::default();
+        let line: Vec<char> = line.chars().collect();
+        let heat = FzfScorer.heatmap(&line, &config);
+        let candidates: Vec<Vec<usize>> = query.chars().map(|q| {
+            (0..line.len()).filter(|pos| {line[*pos] == q}).collect()
+        }).collect();
+        let mut alignment = Alignment::default();
+        let score = FzfScorer.align(&heat, &candidates, &config, &mut alignment);
+        (score, alignment.positions)
+    }
+
+    #[test]
+    fn fzf_scores_like_v2() {
+        // two boundaries: 16 + 8 * 2, a gap of three, 16 + 8
+        assert_eq!(fzf_align("foo-bar", "fb"), (Some(32 - 5 + 24), vec![0, 4]));
+        // a camel case hump: 16 + 8 * 2, a gap of two, 16 + 7
+        assert_eq!(fzf_align("fooBar", "fB"), (Some(32 - 4 + 23), vec![0, 3]));
+        // the run after the space keeps its first bonus, which beats two
+        // boundaries with a gap between them
+        assert_eq!(fzf_align("a-b ab", "ab"), (Some(32 + 24), vec![4, 5]));
+        assert_eq!(fzf_align("ab", "ba"), (None, vec![]));
+    }
+
+    // the bis heat at the first character of word in line
+    fn heat_at(line: &str, word: &str) -> isize {
+        let chars: Vec<char> = line.chars().collect();
+        let heat = BisScorer.heatmap(&chars, &Config::default());
+        heat[line[..line.find(word).unwrap()].chars().count()]
+    }
+
+    #[test]
+    fn shell_syntax_heat() {
+        // each line against one with an ordinary character of the same
+        // class in place of the syntax
+        let config = Config::default();
+        assert_eq!(heat_at("cat log | grep x", "grep") - heat_at("cat log . grep x", "grep"),
+                   config.command_factor);
+        assert_eq!(heat_at("make && test", "test") - heat_at("make .. test", "test"),
+                   config.command_factor);
+        assert_eq!(heat_at("make; test", "test") - heat_at("make. test", "test"),
+                   config.command_factor);
+        assert_eq!(heat_at("echo $(date)", "date") - heat_at("echo #(date)", "date"),
+                   config.command_factor);
+        assert_eq!(heat_at("run --env=prod", "prod") - heat_at("run --env:prod", "prod"),
+                   config.assign_factor);
+        assert_eq!(heat_at("ls src/main.rs", "main") - heat_at("ls src:main.rs", "main"),
+                   config.path_factor);
+        assert_eq!(heat_at("kubectl --deploy", "deploy") - heat_at("kubectl ::deploy", "deploy"),
+                   config.flag_factor);
+    }
+
+    // the named parts bis gives positions in a line with even heat
+    fn bis_parts(positions: &[usize], config: &Config) -> Vec<(&'static str, isize)> {
+        BisScorer.explain(&[1; 20], positions, config)
+    }
+
+    #[test]
+    fn bis_runs_and_leads() {
+        let config = Config::default();
+        let part = |positions: &[usize], name: &str| {
+            bis_parts(positions, &config).into_iter().find(|&(n, _)| {n == name}).unwrap().1
+        };
+        // a run beats the same characters spread out
+        assert_eq!(part(&[2, 3, 4], "run"), 2 * config.run_weight);
+        assert_eq!(part(&[2, 5, 8], "run"), 0);
+        assert!(BisScorer.score(&[1; 20], &[2, 3, 4], &config) > BisScorer.score(&[1; 20], &[2, 5, 8], &config));
+        // starting later costs for every character skipped
+        assert_eq!(part(&[0, 1], "lead"), 0);
+        assert_eq!(part(&[6, 7], "lead"), 6 * config.lead_weight);
+        assert!(BisScorer.score(&[1; 20], &[0, 1], &config) > BisScorer.score(&[1; 20], &[6, 7], &config));
+    }
+
+    #[test]
+    fn bis_parts_weighted_zero() {
+        let positions = [4, 5, 9];
+        let default = Config::default();
+        let total = BisScorer.score(&[1; 20], &positions, &default);
+        for name in ["run", "lead"].iter() {
+            let mut config = Config::default();
+            match *name {
+                "run" => config.run_weight = 0,
+                _ => config.lead_weight = 0
+            }
+            let before = bis_parts(&positions, &default).into_iter().find(|&(n, _)| {n == *name}).unwrap().1;
+            assert!(before != 0);
+            // the part is gone, and so is what it added to the total
+            let parts = bis_parts(&positions, &config);
+            assert_eq!(parts.iter().find(|&&(n, _)| {n == *name}).unwrap().1, 0);
+            assert_eq!(parts.iter().map(|&(_, value)| {value}).sum::<isize>(), total - before);
+            assert_eq!(BisScorer.score(&[1; 20], &positions, &config), total - before);
+        }
+    }
+
+    #[test]
+    fn align_long_line() {
+        // every position of a long line is a candidate for every character
+        let config = Config::default();
+        let heat = vec![1; 400];
+        let candidates = vec![(0..400).collect::<Vec<usize>>(); 6];
+        let mut alignment = Alignment::default();
+        for scorer in [&BisScorer as &Scorer, &FlxScorer, &FzfScorer].iter() {
+            assert!(scorer.align(&heat, &candidates, &config, &mut alignment).is_some());
+            assert_eq!(alignment.positions.len(), 6);
+        }
+    }
+}
@@ -11,54 +11,155 @@
 // WARRANTIES OR CONDITIONS OF ANY KIND, either expressed or implied. See the
 // License for the specific language concerning governing permissions and
 // limitations under the License.
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
 use std::collections::{HashMap, BinaryHeap};
 use std::borrow::{Cow, IntoCow};
+use std::hash::{Hash, Hasher, SipHasher};
+use std::mem;
+use std::iter;
+
+use regex::{self, Regex};
 
 use std::cmp;
+use std::fmt;
 use std::path;
 
-use error::StringError;
-use constants::*;
-
-#[derive(PartialEq)]
-enum CharClass {
-    Whitespace,
-    Numeric,
-    Alphabetic,
-    First,
-    Other
-}
+use error::BisError;
+use history::{Command, read_commands};
+use config::Config;
+use query::{Query, Term, TermKind, Haystack};
+use fold::{QueryChar, fold_query, matches_required};
+use scorer::{Scorer, Alignment};
+use frecency::Frecency;
+use index::{Index, Line, Span, char_bit};
 
+// positions and heat are in the folded line, the index maps them back to
+// the original characters
 #[derive(Debug)]
 struct LineInfo {
-    char_map: HashMap<char, Vec<usize>>,
-    heatmap: Vec<isize>,
-    pub factor: isize
+    // where the most recent spelling of the line is in the index
+    span: Span,
+    // the last line number, for recency
+    pub factor: isize,
+    occurrences: Occurrences,
+    // the next line whose normalized form hashes the same
+    next: Option<u32>
+}
+
+// how often and when a line was run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Occurrences {
+    pub count: usize,
+    // line numbers across every history file read
+    pub first: isize,
+    pub last: isize,
+    // unix times, if the history records them
+    pub first_time: Option<u64>,
+    pub last_time: Option<u64>
 }
 
 #[derive(Debug)]
 pub struct LineMatch {
     score: isize,
     factor: isize,
-    line: Cow<'static, str>
+    // which line in the search base
+    id: u32,
+    // filled in once the matches are picked
+    line: Cow<'static, str>,
+    // the matched char positions of each query term
+    positions: Vec<Vec<usize>>,
+    occurrences: Occurrences,
+    explanation: Option<Explanation>
+}
+
+// why a line scored what it did
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    // the positions each term matched at in the original line, and the
+    // heat at each of them
+    pub positions: Vec<Vec<usize>>,
+    pub heat: Vec<Vec<isize>>,
+    // named parts of the score, which add up to total
+    pub parts: Vec<(String, isize)>,
+    pub total: isize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Fuzzy,
+    Regex
+}
+
+#[derive(Debug)]
+pub struct QueryResult {
+    pub matches: Vec<LineMatch>,
+    // something the user should know about these matches
+    pub status: Option<String>,
+    // how many lines matched in all, and how many better ones were skipped
+    pub total: usize,
+    pub offset: usize
 }
 
+// lines are found by a hash of their normalized form, with a chain
+// through LineInfo.next for forms that hash the same
 #[derive(Debug)]
 pub struct SearchBase {
-    lines: HashMap<Cow<'static, str>, LineInfo>
+    index: Index,
+    lines: Vec<LineInfo>,
+    keys: HashMap<u64, u32>,
+    line_number: isize,
+    scorer: Box<Scorer + Send>,
+    frecency: Frecency,
+    // the normalized form of each line picked before
+    picked: HashMap<u32, String>,
+    // whether respelled lines left text in the index nothing points to
+    stale: bool,
+    config: Config
 }
 
-impl Default for SearchBase {
-    fn default() -> SearchBase {
-        SearchBase {
-            lines: HashMap::default()
-        }
-    }
+// buffers reused from one line to the next, each thread searching needs
+// its own
+#[derive(Debug, Default)]
+pub struct Scratch {
+    original: Vec<char>,
+    haystack: Haystack,
+    heat: Vec<isize>,
+    // the positions each query character could match at
+    candidates: Vec<Vec<usize>>,
+    alignment: Alignment,
+    // the best term of each group with its score and typos, and its folded positions
+    best: Vec<(usize, isize, usize)>,
+    positions: Vec<Vec<usize>>,
+    // the positions of a match that was dropped, to reuse for the next one
+    spare: Vec<Vec<Vec<usize>>>,
+    typos: Typos
 }
 
+// buffers for matching with typos
+#[derive(Debug, Default)]
+struct Typos {
+    // the query characters that aren't whitespace
+    chars: Vec<QueryChar>,
+    // how far into the line the first so many characters get with so many
+    // edits, and the step that got there
+    reach: Vec<Option<usize>>,
+    steps: Vec<Step>,
+    // the query with the fewest edits that matches
+    edited: Vec<QueryChar>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Start,
+    Match,
+    // a character typed by mistake, or instead of another
+    Drop,
+    // two characters typed the wrong way round
+    Swap
+}
+
+// ties go to the more recent line. factor is the line number a line was
+// last run at, which no other line has, so that always decides and the
+// same query always gives the same order
 impl Ord for LineMatch {
     fn cmp(&self, other: &LineMatch) -> cmp::Ordering {
         match self.score.cmp(&other.score) {
@@ -82,39 +183,306 @@ impl PartialEq for LineMatch {
 
 impl Eq for LineMatch {}
 
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "heat"));
+        for group in self.heat.iter() {
+            let values: Vec<String> = group.iter().map(|h| {format!("{}", h)}).collect();
+            try!(write!(f, " [{}]", values.join(" ")));
+        }
+        for &(ref name, value) in self.parts.iter() {
+            try!(write!(f, " {} {}", name, value));
+        }
+        write!(f, " = {}", self.total)
+    }
+}
+
+impl LineMatch {
+    pub fn line(&self) -> &Cow<'static, str> {
+        &self.line
+    }
+
+    pub fn positions(&self) -> &[Vec<usize>] {
+        &self.positions
+    }
+
+    pub fn occurrences(&self) -> &Occurrences {
+        &self.occurrences
+    }
+
+    pub fn explanation(&self) -> Option<&Explanation> {
+        self.explanation.as_ref()
+    }
+}
+
+// keep match_item if it is among the limit best seen so far, returning
+// the match that made room for it
+fn keep_match(matches: &mut BinaryHeap<LineMatch>, limit: usize, match_item: LineMatch) -> Option<LineMatch> {
+    if matches.len() < limit {
+        matches.push(match_item);
+        return None;
+    }
+
+    let insert = match matches.peek() {
+        None => false,
+        Some(worst) => &match_item < worst
+    };
+    if insert {
+        Some(matches.push_pop(match_item))
+    } else {
+        Some(match_item)
+    }
+}
+
+// a term folded once per query instead of once per line
+struct Prepared<'a> {
+    term: &'a Term,
+    folded: Vec<QueryChar>,
+    // how many typos are allowed
+    typos: usize,
+    // the characters a matching line has, less up to typos of them
+    signature: u64,
+    // case sensitive terms check the original characters
+    original: bool
+}
+
+// the prepared terms of each group
+fn prepare<'a>(query: &'a Query, tolerant: bool, config: &Config) -> Vec<Vec<Prepared<'a>>> {
+    query.groups.iter().map(|group| {
+        group.iter().map(|term| {
+            let folded = fold_query(&term.text, config.case);
+            let typos = if tolerant && config.typo_every > 0 {
+                folded.len() / config.typo_every
+            } else {
+                0
+            };
+            let signature = folded.iter().filter(|&&(c, _)| {!c.is_whitespace()}).fold(0, |sig, &(c, _)| {
+                sig | char_bit(c)
+            });
+            let original = folded.iter().any(|&(_, required)| {required.is_some()});
+
+            Prepared {
+                term: term,
+                folded: folded,
+                typos: typos,
+                signature: signature,
+                original: original
+            }
+        }).collect()
+    }).collect()
+}
+
+// whether a line with signature could match every group. each typo can
+// drop one character of a term, so that many may be missing
+fn plausible(signature: u64, query: &[Vec<Prepared>]) -> bool {
+    query.iter().all(|group| {
+        group.iter().any(|prepared| {
+            // exact terms match the original line, so anything goes
+            prepared.term.negated || prepared.term.kind != TermKind::Fuzzy ||
+                (prepared.signature & !signature).count_ones() as usize <= prepared.typos
+        })
+    })
+}
+
+// whether keep_match would keep a match, before building it
+fn would_keep(matches: &BinaryHeap<LineMatch>, limit: usize, score: isize, factor: isize) -> bool {
+    if matches.len() < limit {
+        return true;
+    }
+    match matches.peek() {
+        None => false,
+        Some(worst) => (score, factor) < (worst.score, worst.factor)
+    }
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = SipHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl SearchBase {
-    pub fn read_history<T: AsRef<path::Path>>(&mut self, path: T) -> Result<isize, StringError> {
-        let input_file = match File::open(path) {
-            Ok(f) => BufReader::new(f),
-            Err(e) => return Err(StringError::new("Could not open history file", Some(Box::new(e))))
+    pub fn new(config: Config) -> SearchBase {
+        SearchBase {
+            index: Index::default(),
+            lines: vec![],
+            keys: HashMap::default(),
+            line_number: -1,
+            scorer: config.scorer.create(),
+            frecency: Frecency::default(),
+            picked: HashMap::default(),
+            stale: false,
+            config: config
+        }
+    }
+
+    // files read later count as more recent
+    pub fn read_history<T: AsRef<path::Path>>(&mut self, path: T) -> Result<isize, BisError> {
+        let commands = try!(read_commands(path));
+
+        for Command {text, time} in commands.into_iter() {
+            self.add_line(text, time);
+        }
+
+        Ok(self.line_number)
+    }
+
+    // a line run after everything read so far
+    pub fn add_line(&mut self, text: String, time: Option<u64>) {
+        self.line_number += 1;
+
+        if !self.index.has_room(&text) {
+            warn!("History too large to index, skipping {:?}", text);
+            return;
+        }
+
+        let key = self.config.normalize.apply(&text);
+        let hash = hash_key(&key);
+
+        match self.find(hash, &key) {
+            Some(id) => {
+                let id = id as usize;
+                if self.index.line(&self.lines[id].span).text() != text {
+                    // show and insert the most recent spelling, the old one
+                    // stays in the index until shrink_to_fit drops it
+                    trace!("Respelling {:?} as {:?}", self.index.line(&self.lines[id].span).text(), text);
+                    self.lines[id].span = self.index.push(&text, &*self.scorer, &self.config);
+                    self.stale = true;
+                }
+
+                // run again
+                self.lines[id].seen(self.line_number, time);
+            },
+            None => {
+                // index the line
+                let span = self.index.push(&text, &*self.scorer, &self.config);
+                let id = self.lines.len() as u32;
+
+                // it goes at the head of the chain for its hash
+                let next = self.keys.insert(hash, id);
+                self.lines.push(LineInfo::new(span, self.line_number, time, next));
+
+                if self.frecency.contains(&key) {
+                    self.picked.insert(id, key);
+                }
+            }
+        }
+    }
+
+    // the line whose normalized form is key
+    fn find(&self, hash: u64, key: &str) -> Option<u32> {
+        let mut next = self.keys.get(&hash).cloned();
+        while let Some(id) = next {
+            let info = &self.lines[id as usize];
+            if self.config.normalize.apply(self.index.line(&info.span).text()) == key {
+                return Some(id);
+            }
+            next = info.next;
+        }
+        None
+    }
+
+    // bytes allocated for the index and the lines pointing into it
+    pub fn memory(&self) -> usize {
+        self.index.memory() +
+            self.lines.capacity() * mem::size_of::<LineInfo>() +
+            self.keys.capacity() * (mem::size_of::<u64>() + mem::size_of::<u32>())
+    }
+
+    // give back what growing the index and respelling lines left unused
+    pub fn shrink_to_fit(&mut self) {
+        if self.stale {
+            // copy out only the spellings still shown
+            debug!("Dropping old spellings from the index");
+            let mut index = Index::default();
+            for info in self.lines.iter_mut() {
+                info.span = index.copy(&self.index, &info.span);
+            }
+            self.index = index;
+            self.stale = false;
+        }
+
+        self.index.shrink_to_fit();
+        self.lines.shrink_to_fit();
+        self.keys.shrink_to_fit();
+    }
+
+    pub fn read_selections(&mut self) {
+        debug!("Reading selection log");
+        self.frecency = match Frecency::load(&self.config) {
+            Ok(frecency) => frecency,
+            Err(e) => {
+                // picks only help the ranking, search without them
+                warn!("Failed to read selection log: {}", e);
+                Frecency::default()
+            }
         };
 
-        let mut line_number = -1;
+        // find the lines already read that were picked, under any spelling
+        self.picked.clear();
+        for key in self.frecency.keys() {
+            match self.find(hash_key(key), key) {
+                Some(id) => {
+                    self.picked.insert(id, key.clone());
+                },
+                None => {
+                    trace!("Picked line not in history: {:?}", key);
+                }
+            }
+        }
+    }
 
-        for m_line in input_file.lines() {
-            let line = match m_line {
-                Ok(line) => line,
+    // the bonus for line id having been picked before, when searching for query
+    fn frecency_score(&self, id: u32, query: &str) -> isize {
+        match self.picked.get(&id) {
+            Some(key) => self.frecency.score(key, query, &self.config),
+            None => 0
+        }
+    }
+
+    pub fn read_histories(&mut self, paths: &[path::PathBuf]) -> Result<(), BisError> {
+        info!("Reading history");
+        for path in paths.iter() {
+            debug!("Reading history from {:?}", path);
+            match self.read_history(path) {
+                Ok(_) => {
+                    // success
+                },
                 Err(e) => {
-                    return Err(StringError::new("Failed to read line", Some(Box::new(e))));
+                    return Err(BisError::wrap("Failed to read history", e));
                 }
-            };
+            }
+        }
 
-            line_number += 1;
+        self.shrink_to_fit();
+        debug!("Indexed {} lines in {} bytes", self.lines.len(), self.memory());
 
-            // generate the line info
-            let info = LineInfo::new(&line, line_number);
+        Ok(())
+    }
 
-            // insert the line into the map
-            self.lines.insert(line.into_cow(), info);
+    // keeps the limit best matches and returns how many lines matched,
+    // tolerant allows typos in fuzzy terms, at a cost
+    pub fn query_inplace(&self, query: &Query, tolerant: bool, scratch: &mut Scratch, limit: usize,
+                         matches: &mut BinaryHeap<LineMatch>) -> usize {
+        if query.is_empty() {
+            // nothing to search for
+            return 0;
         }
 
-        Ok(line_number)
-    }
+        let prepared = prepare(query, tolerant, &self.config);
+        let mut candidates = 0;
+        let mut total = 0;
 
-    pub fn query_inplace<T: AsRef<str>>(&self, query: T, matches: &mut BinaryHeap<LineMatch>) {
         // search for a match
-        for (line, info) in self.lines.iter() {
-            let line_score = match info.query_score(&query) {
+        for (id, info) in self.lines.iter().enumerate() {
+            let line = self.index.line(&info.span);
+            if !plausible(line.signature(), &prepared) {
+                // missing a character the query needs
+                continue;
+            }
+            candidates += 1;
+
+            let line_score = match info.query_score(&line, &prepared, scratch, &*self.scorer, &self.config) {
                 None => {
                     // non-matching line
                     continue;
@@ -124,296 +492,843 @@ impl SearchBase {
                 }
             };
 
+            // lines picked before, especially for this query
+            let line_score = line_score + self.frecency_score(id as u32, &query.text);
+            total += 1;
+
             // negate everything so we can use push_pop
+            if !would_keep(matches, limit, -line_score, -info.factor) {
+                continue;
+            }
+
+            // report positions in the original line, in the buffers of a
+            // match dropped earlier if there is one
+            let mut positions = scratch.spare.pop().unwrap_or(vec![]);
+            positions.truncate(prepared.len());
+            while positions.len() < prepared.len() {
+                positions.push(vec![]);
+            }
+            for (original, pgroup) in positions.iter_mut().zip(scratch.positions.iter()) {
+                original.clear();
+                original.extend(pgroup.iter().map(|pos| {line.original(*pos)}));
+                original.dedup();
+            }
+
             let match_item = LineMatch {
                 score: -line_score,
                 factor: -info.factor,
-                line: line.clone()
+                id: id as u32,
+                line: Cow::Borrowed(""),
+                positions: positions,
+                occurrences: info.occurrences,
+                explanation: None
             };
-            let matches_len = matches.len();
-            let matches_capacity = matches.capacity();
-            let insert;
-            match matches.peek() {
-                None => {
-                    insert = true;
-                },
-                Some(item) => {
-                    if &match_item < item || matches_len < matches_capacity {
-                        insert = true
-                    } else {
-                        insert = false;
-                    }
-                }
-            }
-            if insert {
-                if matches_len < matches_capacity {
-                    matches.push(match_item);
-                } else {
-                    matches.push_pop(match_item);
-                }
+            match keep_match(matches, limit, match_item) {
+                Some(dropped) => scratch.spare.push(dropped.positions),
+                None => {}
             }
         }
+
+        trace!("Aligned {} of {} lines", candidates, self.lines.len());
+
+        total
     }
 
-    pub fn query<T: AsRef<str>>(&self, query: T) -> Vec<Cow<'static, str>> {
-        // allocate the match object
-        let mut matches: BinaryHeap<LineMatch> = BinaryHeap::with_capacity(MATCH_NUMBER);
+    // regex matches have no heat, so they're ordered by how recently and
+    // how often they were run and picked. keeps the limit best matches and
+    // returns how many lines matched
+    pub fn query_regex(&self, pattern: &str, limit: usize,
+                       matches: &mut BinaryHeap<LineMatch>) -> Result<usize, regex::Error> {
+        // regexes are case sensitive or not as a whole
+        let re = if self.config.case.is_sensitive_pattern(pattern) {
+            try!(Regex::new(pattern))
+        } else {
+            try!(Regex::new(&format!("(?i){}", pattern)))
+        };
+
+        let mut total = 0;
+        for (id, info) in self.lines.iter().enumerate() {
+            let line = self.index.line(&info.span).text();
+            if !re.is_match(line) {
+                continue;
+            }
+            total += 1;
+
+            // the same history terms a fuzzy match gets
+            let line_score = info.recency_score(&self.config) + info.count_score(&self.config) +
+                self.frecency_score(id as u32, pattern);
+            if !would_keep(matches, limit, -line_score, -info.factor) {
+                continue;
+            }
+
+            let spans: Vec<(usize, usize)> = re.find_iter(line).collect();
+
+            // turn byte spans into char positions
+            let positions = line.char_indices().enumerate().filter(|&(_, (byte, _))| {
+                spans.iter().any(|&(start, end)| {byte >= start && byte < end})
+            }).map(|(idx, _)| {idx}).collect();
 
-        self.query_inplace(query, &mut matches);
+            keep_match(matches, limit, LineMatch {
+                score: -line_score,
+                factor: -info.factor,
+                id: id as u32,
+                line: Cow::Borrowed(""),
+                positions: vec![positions],
+                occurrences: info.occurrences,
+                explanation: None
+            });
+        }
 
-        // result contains a vector of the top MATCH_NUMBER lines, in descending score order
-        matches.into_sorted_vec().into_iter().map(|x| {x.line}).collect()
+        Ok(total)
     }
-}
 
-impl LineInfo {
-    fn new<T: AsRef<str>>(item: T, factor: isize) -> LineInfo {
-        let mut map: HashMap<char, Vec<usize>> = HashMap::new();
-        let mut heat = vec![];
-        let line = item.as_ref();
-
-        let mut ws_score = 0;
-        let mut cs_score = 0;
-        let mut cur_class = CharClass::First;
-        // character class changes don't stack
-        let mut cs_change = false;
-
-        for (idx, c) in line.chars().enumerate() {
-            // don't process characters beyond MAX_LEN
-            if idx > MAX_LEN {
-                break;
-            }
-
-            // don't map whitespace
-            if !c.is_whitespace() {
-                // update the character class change score if needed
-                if cur_class == CharClass::First {
-                    // add the first character factor on top of class change
-                    cs_score += FIRST_FACTOR;
-                }
-                if c.is_numeric() {
-                    if cur_class != CharClass::Numeric {
-                        cur_class = CharClass::Numeric;
-                        if !cs_change {
-                            cs_score += CLASS_FACTOR;
-                            cs_change = true;
-                        }
-                    } else {
-                        cs_change = false;
-                    }
-                } else if c.is_alphabetic() {
-                    if cur_class != CharClass::Alphabetic {
-                        cur_class = CharClass::Alphabetic;
-                        if !cs_change {
-                            cs_score += CLASS_FACTOR;
-                            cs_change = true;
-                        }
-                    } else {
-                        cs_change = false;
-                    }
-                } else {
-                    if cur_class != CharClass::Other {
-                        cur_class = CharClass::Other;
-                        if !cs_change {
-                            cs_score += CLASS_FACTOR;
-                            cs_change = true;
-                        }
-                    } else {
-                        cs_change = false;
+    // the k best matches after skipping offset of them. explain fills in
+    // why each fuzzy match scored what it did
+    pub fn query<T: AsRef<str>>(&self, query: T, mode: SearchMode, explain: bool, k: usize, offset: usize,
+                                scratch: &mut Scratch) -> QueryResult {
+        // the skipped matches have to be found to know what comes after them
+        let limit = offset.saturating_add(k);
+        let mut matches: BinaryHeap<LineMatch> = BinaryHeap::with_capacity(cmp::min(limit, self.lines.len()));
+        let mut status = None;
+        let mut total = 0;
+        let mut parsed = None;
+        let mut tolerant = false;
+
+        match mode {
+            SearchMode::Fuzzy => {
+                let fuzzy = Query::parse(query);
+                total = self.query_inplace(&fuzzy, false, scratch, limit, &mut matches);
+
+                // one wrong keystroke shouldn't empty the list
+                if total == 0 && self.config.typo_every > 0 {
+                    tolerant = true;
+                    total = self.query_inplace(&fuzzy, true, scratch, limit, &mut matches);
+                    if total > 0 {
+                        debug!("Showing approximate matches");
+                        status = Some("no exact matches \u{2014} showing approximate".to_owned());
                     }
                 }
 
-                // add an entry in the character map
-                map.entry(c).or_insert(Vec::new()).push(idx);
-                if c.is_uppercase() {
-                    for lc in c.to_lowercase() {
-                        // also insert all lowercase equivalents of this character
-                        // but not the other way around, so that typing something
-                        // uppercase specifies to match uppercase
-                        map.entry(lc).or_insert(Vec::new()).push(idx);
+                parsed = Some(fuzzy);
+            },
+            SearchMode::Regex => {
+                match self.query_regex(query.as_ref(), limit, &mut matches) {
+                    Ok(count) => {
+                        total = count;
+                    },
+                    Err(e) => {
+                        // most likely still being typed
+                        debug!("Invalid regex: {}", e);
+                        status = Some(format!("Invalid regex: {}", e));
                     }
                 }
-            } else {
-                // whitespace is treated differently
-                cur_class = CharClass::Whitespace;
-                ws_score = WHITESPACE_FACTOR;
             }
+        }
 
-            // push to the heatmap
-            heat.push(ws_score + cs_score);
+        // the best first, without the ones skipped
+        let mut page: Vec<LineMatch> = matches.into_sorted_vec().into_iter().skip(offset).collect();
 
-            // reduce things
-            ws_score /= WHITESPACE_REDUCE;
-            if !cs_change {
-                cs_score /= CLASS_REDUCE;
-            }
+        for item in page.iter_mut() {
+            // only the matches shown get a copy of their line
+            item.line = self.index.line(&self.lines[item.id as usize].span).text().to_owned().into_cow();
+        }
+
+        match parsed {
+            Some(ref parsed) if explain => {
+                // only worth the work for the matches shown
+                let prepared = prepare(parsed, tolerant, &self.config);
+                for item in page.iter_mut() {
+                    let id = item.id;
+                    let info = &self.lines[id as usize];
+                    let line = self.index.line(&info.span);
+                    item.explanation = info.explain(&line, &prepared, scratch, &*self.scorer,
+                                                    &self.config).map(|mut explanation| {
+                        let frecency = self.frecency_score(id, &parsed.text);
+                        explanation.parts.push(("frecency".to_owned(), frecency));
+                        explanation.total += frecency;
+                        explanation
+                    });
+                }
+            },
+            _ => {}
         }
 
+        QueryResult {
+            matches: page,
+            status: status,
+            total: total,
+            offset: offset
+        }
+    }
+}
+
+impl LineInfo {
+    fn new(span: Span, factor: isize, time: Option<u64>, next: Option<u32>) -> LineInfo {
         LineInfo {
-            char_map: map,
-            heatmap: heat,
-            factor: factor
+            span: span,
+            factor: factor,
+            occurrences: Occurrences {
+                count: 1,
+                first: factor,
+                last: factor,
+                first_time: time,
+                last_time: time
+            },
+            next: next
+        }
+    }
+
+    // add the positions c could match at to list, in increasing order
+    fn char_positions(&self, line: &Line, c: char, required: Option<char>, original: &[char],
+                      list: &mut Vec<usize>) {
+        list.clear();
+        line.positions(c, list);
+
+        match required {
+            None => {},
+            Some(r) => {
+                // case sensitive, the original character has to be r
+                list.retain(|pos| {
+                    match original.get(line.original(*pos)) {
+                        Some(o) => matches_required(*o, r),
+                        None => false
+                    }
+                });
+            }
         }
     }
 
-    fn query_sequence<T: AsRef<str>>(&self, query_item: T) -> Option<Vec<Vec<usize>>> {
-        let query = query_item.as_ref();
-        let mut positions: Vec<Vec<usize>> = vec![];
-        let mut match_len = query.len();
+    // fills candidates with the positions each query character could match
+    // at and returns how many there are. on failure, returns the index of
+    // the query character that couldn't be placed
+    fn query_sequence(&self, line: &Line, query: &[QueryChar], original: &[char],
+                      candidates: &mut Vec<Vec<usize>>) -> Result<usize, usize> {
+        let mut used = 0;
 
-        for c in query.chars() {
+        for (i, &(c, required)) in query.iter().enumerate() {
             // ignore whitespace characters
             if c.is_whitespace() {
                 trace!("Skipping whitespace character");
-                match_len -= 1;
                 continue;
             }
 
             trace!("Matching character: {:?}", c);
-            match self.char_map.get(&c) {
-                None => break,
-                Some(list) => {
-                    let to_push;
-                    match positions.last() {
-                        None => {
-                            to_push = list.clone();
-                        },
-                        Some(item) => {
-                            match list.binary_search(&item[0]) {
-                                Ok(idx) => {
-                                    if idx >= list.len() - 1 {
-                                        // line is non-matching
-                                        break;
-                                    } else {
-                                        to_push = list.split_at(idx + 1).1.into();
-                                    }
-                                },
-                                Err(idx) => {
-                                    if idx >= list.len() {
-                                        // line is non-matching
-                                        break;
-                                    } else {
-                                        to_push = list.split_at(idx).1.into();
-                                    }
-                                }
-                            }
+            if candidates.len() == used {
+                candidates.push(vec![]);
+            }
+
+            let (before, rest) = candidates.split_at_mut(used);
+            let list = &mut rest[0];
+            self.char_positions(line, c, required, original, list);
+
+            match before.last() {
+                None => {},
+                Some(item) => {
+                    // only positions after the first one of the last character
+                    let first = item[0];
+                    list.retain(|pos| {*pos > first});
+                }
+            }
+
+            if list.is_empty() {
+                // line is non-matching
+                return Err(i);
+            }
+
+            used += 1;
+        }
+
+        Ok(used)
+    }
+
+    // candidates for query allowing up to typos edits, how many were needed
+    // and how many candidate lists there are
+    //
+    // an edit drops a query character, which is as good as substituting it
+    // for a subsequence match, or swaps two next to each other. matching
+    // each character as early as it can, a table of how far into the line
+    // each prefix of the query gets with each number of edits finds the
+    // fewest edits that match, and the query with them is matched as usual
+    fn approximate_sequence(&self, line: &Line, query: &[QueryChar], original: &[char], typos: usize,
+                            candidates: &mut Vec<Vec<usize>>, scratch: &mut Typos) -> Option<(usize, usize)> {
+        match self.query_sequence(line, query, original, candidates) {
+            Ok(used) => return Some((0, used)),
+            Err(_) if typos == 0 => return None,
+            Err(_) => {}
+        }
+
+        scratch.chars.clear();
+        scratch.chars.extend(query.iter().cloned().filter(|&(c, _)| {!c.is_whitespace()}));
+        let chars = &scratch.chars;
+        let n = chars.len();
+
+        // every position each character could match at
+        while candidates.len() < n {
+            candidates.push(vec![]);
+        }
+        for (i, &(c, required)) in chars.iter().enumerate() {
+            self.char_positions(line, c, required, original, &mut candidates[i]);
+        }
+
+        // the table borrows candidates until the edited query is known
+        let edits = {
+            // just past where character i first matches at or after from
+            let next = |i: usize, from: usize| -> Option<usize> {
+                let list = &candidates[i];
+                let idx = match list.binary_search(&from) {
+                    Ok(idx) | Err(idx) => idx
+                };
+                list.get(idx).map(|pos| {*pos + 1})
+            };
+
+            let width = n + 1;
+            let reach = &mut scratch.reach;
+            let steps = &mut scratch.steps;
+            reach.clear();
+            reach.extend(iter::repeat(None).take((typos + 1) * width));
+            steps.clear();
+            steps.extend(iter::repeat(Step::Start).take((typos + 1) * width));
+            reach[0] = Some(0);
+
+            // keep the step that gets least far, and a match over an edit
+            fn step(reach: &mut [Option<usize>], steps: &mut [Step], cell: usize, to: Option<usize>, how: Step) {
+                let better = match (to, reach[cell]) {
+                    (None, _) => false,
+                    (Some(_), None) => true,
+                    (Some(to), Some(last)) => to < last || (to == last && how == Step::Match)
+                };
+                if better {
+                    reach[cell] = to;
+                    steps[cell] = how;
+                }
+            }
+
+            for edits in 0..typos + 1 {
+                for i in 0..n {
+                    let from = match reach[edits * width + i] {
+                        Some(from) => from,
+                        None => continue
+                    };
+
+                    step(reach, steps, edits * width + i + 1, next(i, from), Step::Match);
+                    if edits < typos {
+                        step(reach, steps, (edits + 1) * width + i + 1, Some(from), Step::Drop);
+                        if i + 1 < n {
+                            let swapped = next(i + 1, from).and_then(|after| {next(i, after)});
+                            step(reach, steps, (edits + 1) * width + i + 2, swapped, Step::Swap);
                         }
                     }
-                    positions.push(to_push);
                 }
             }
+
+            // the fewest edits that get through the whole query, not dropping all of it
+            let edits = match (0..typos + 1).find(|edits| {
+                *edits < n && reach[*edits * width + n].is_some()
+            }) {
+                Some(edits) => edits,
+                None => return None
+            };
+
+            // walk back to the edited query
+            scratch.edited.clear();
+            let mut e = edits;
+            let mut i = n;
+            while i > 0 {
+                match steps[e * width + i] {
+                    Step::Match => {
+                        scratch.edited.push(chars[i - 1]);
+                        i -= 1;
+                    },
+                    Step::Drop => {
+                        e -= 1;
+                        i -= 1;
+                    },
+                    Step::Swap => {
+                        scratch.edited.push(chars[i - 2]);
+                        scratch.edited.push(chars[i - 1]);
+                        e -= 1;
+                        i -= 2;
+                    },
+                    Step::Start => unreachable!()
+                }
+            }
+            scratch.edited.reverse();
+
+            edits
+        };
+
+        match self.query_sequence(line, &scratch.edited, original, candidates) {
+            Ok(used) => Some((edits, used)),
+            // can't happen, the table found a match
+            Err(_) => None
+        }
+    }
+
+    // the score and number of typos of a fuzzy match, its folded positions
+    // are left in scratch.alignment
+    fn fuzzy_score(&self, line: &Line, term: &Prepared, scratch: &mut Scratch, scorer: &Scorer,
+                   config: &Config) -> Option<(isize, usize)> {
+        // only look at the original characters if we have to
+        scratch.original.clear();
+        if term.original {
+            scratch.original.extend(line.text().chars());
         }
 
-        if positions.len() == match_len {
-            Some(positions)
+        let (edits, used) = match self.approximate_sequence(line, &term.folded, &scratch.original, term.typos,
+                                                            &mut scratch.candidates, &mut scratch.typos) {
+            None => return None,
+            Some(result) => result
+        };
+
+        // heat is only worth widening for lines that match
+        let heat = line.heat(&mut scratch.heat);
+        scorer.align(heat, &scratch.candidates[..used], config, &mut scratch.alignment).map(|score| {
+            (score - edits as isize * config.typo_penalty, edits)
+        })
+    }
+
+    // the score and number of typos of a term, its folded positions are
+    // left in scratch.alignment
+    fn term_score(&self, line: &Line, term: &Prepared, scratch: &mut Scratch, scorer: &Scorer,
+                  config: &Config) -> Option<(isize, usize)> {
+        if term.term.negated {
+            // excluded terms only filter
+            if term.term.find(&term.folded, line.text(), &mut scratch.haystack) {
+                None
+            } else {
+                scratch.alignment.positions.clear();
+                Some((0, 0))
+            }
+        } else if term.term.kind == TermKind::Fuzzy {
+            self.fuzzy_score(line, term, scratch, scorer, config)
         } else {
-            None
-        }
-    }
-
-    fn query_positions<T: AsRef<str>>(&self, query: T) -> Option<Vec<Vec<usize>>> {
-        match self.query_sequence(query) {
-            None => None,
-            Some(positions) => {
-                // matching line
-                // create our idx vector
-                let mut idx = vec![0; positions.len()];
-                let mut result = vec![];
-                loop {
-                    // check that current configuration is strictly increasing
-                    let mut ignore = false;
-                    {
-                        let mut last_pos = None;
-                        for (i, pos) in idx.iter().enumerate() {
-                            match last_pos {
-                                None => last_pos = Some(positions[i][*pos]),
-                                Some(other) => {
-                                    if other >= positions[i][*pos] {
-                                        ignore = true;
-                                        break;
-                                    } else {
-                                        last_pos = Some(positions[i][*pos]);
-                                    }
-                                }
-                            }
-                        }
-                    }
+            // exact positions are in the original line, score them in the folded one
+            if !term.term.find(&term.folded, line.text(), &mut scratch.haystack) {
+                return None;
+            }
 
-                    if !ignore {
-                        // add the configuration to the list
-                        result.push(idx.iter().enumerate().map(|(i, pos)| {positions[i][*pos]}).collect());
-                    }
+            let folded = &mut scratch.alignment.positions;
+            folded.clear();
+            folded.extend(scratch.haystack.positions.iter().map(|pos| {line.folded(*pos)}));
+            Some((scorer.score(line.heat(&mut scratch.heat), folded, config), 0))
+        }
+    }
+
+    // the best alternative of each group goes in scratch.best, with its
+    // score and typos, and its folded positions in scratch.positions
+    fn best_terms(&self, line: &Line, query: &[Vec<Prepared>], scratch: &mut Scratch, scorer: &Scorer,
+                  config: &Config) -> bool {
+        scratch.best.clear();
+
+        for (g, group) in query.iter().enumerate() {
+            if scratch.positions.len() == g {
+                scratch.positions.push(vec![]);
+            }
 
-                    // update our position vector
-                    let mut update_idx = idx.len() - 1;
-                    let mut finished = false;
-                    loop {
-                        idx[update_idx] += 1;
-                        if idx[update_idx] >= positions[update_idx].len() {
-                            if update_idx == 0 {
-                                // we're finished with all permutations
-                                finished = true;
-                                break;
-                            } else {
-                                idx[update_idx] = 0;
-                                update_idx -= 1;
-                            }
-                        } else {
-                            // finished updating for this permutation
-                            break;
+            let mut best: Option<(usize, isize, usize)> = None;
+            for (t, term) in group.iter().enumerate() {
+                match self.term_score(line, term, scratch, scorer, config) {
+                    Some((score, edits)) => {
+                        let better = match best {
+                            None => true,
+                            Some((_, last, _)) => score > last
+                        };
+                        if better {
+                            best = Some((t, score, edits));
+                            scratch.positions[g].clear();
+                            scratch.positions[g].extend(scratch.alignment.positions.iter().cloned());
                         }
-                    }
-                    if finished {
-                        // finished with everything
-                        break;
-                    }
+                    },
+                    None => {}
                 }
+            }
 
-                // return result
-                Some(result)
+            match best {
+                None => return false,
+                Some(item) => scratch.best.push(item)
             }
         }
+
+        true
     }
 
-    fn query_score<T: AsRef<str>>(&self, query: T) -> Option<isize> {
-        match self.query_positions(query) {
-            None => None,
-            Some(positions) => {
-                let mut top_score = None;
-                for pgroup in positions.iter() {
-                    // find the average distance between the indexes
-                    let mut dist_total = 0;
-                    let mut dist_count = 0;
-                    for i in 0..pgroup.len() - 1 {
-                        dist_total += (pgroup[i + 1] - pgroup[i]) as isize;
-                        dist_count += 1;
-                    }
-                    // avoid division by zero
-                    if dist_count == 0 {
-                        dist_count = 1;
+    // prefer shorter lines
+    fn length_score(&self, line: &Line, config: &Config) -> isize {
+        -(line.len() as isize / config.length_reduce)
+    }
+
+    fn recency_score(&self, config: &Config) -> isize {
+        self.factor / config.factor_reduce
+    }
+
+    // commands run more often are more likely wanted again
+    fn count_score(&self, config: &Config) -> isize {
+        ((self.occurrences.count as f64).log2() * config.count_weight as f64) as isize
+    }
+
+    // the same line was run again at line number factor
+    fn seen(&mut self, factor: isize, time: Option<u64>) {
+        self.factor = factor;
+        self.occurrences.count += 1;
+        self.occurrences.last = factor;
+        if time.is_some() {
+            if self.occurrences.first_time.is_none() {
+                self.occurrences.first_time = time;
+            }
+            self.occurrences.last_time = time;
+        }
+    }
+
+    fn explain(&self, line: &Line, query: &[Vec<Prepared>], scratch: &mut Scratch, scorer: &Scorer,
+               config: &Config) -> Option<Explanation> {
+        if !self.best_terms(line, query, scratch, scorer, config) {
+            return None;
+        }
+
+        let mut buffer = vec![];
+        let line_heat = line.heat(&mut buffer);
+        let mut positions = vec![];
+        let mut heat = vec![];
+        let mut parts = vec![];
+        let mut total = 0;
+
+        for (g, &(t, score, edits)) in scratch.best.iter().enumerate() {
+            let term = query[g][t].term;
+            let pgroup = &scratch.positions[g];
+
+            total += score;
+            if term.negated {
+                // excluded terms add nothing
+                continue;
+            }
+
+            let mut original: Vec<usize> = pgroup.iter().map(|pos| {line.original(*pos)}).collect();
+            original.dedup();
+            positions.push(original);
+            heat.push(pgroup.iter().map(|pos| {line_heat.get(*pos).cloned().unwrap_or(0)}).collect());
+            for (name, value) in scorer.explain(line_heat, pgroup, config).into_iter() {
+                parts.push((format!("{}:{}", term.text, name), value));
+            }
+            if edits > 0 {
+                parts.push((format!("{}:typos", term.text), -(edits as isize) * config.typo_penalty));
+            }
+        }
+
+        parts.push(("length".to_owned(), self.length_score(line, config)));
+        parts.push(("recency".to_owned(), self.recency_score(config)));
+        parts.push(("count".to_owned(), self.count_score(config)));
+        total += self.length_score(line, config) + self.recency_score(config) + self.count_score(config);
+
+        Some(Explanation {
+            positions: positions,
+            heat: heat,
+            parts: parts,
+            total: total
+        })
+    }
+
+    // each term is matched on its own, so their order in the query doesn't
+    // matter. the positions of each group are left in scratch.positions
+    fn query_score(&self, line: &Line, query: &[Vec<Prepared>], scratch: &mut Scratch, scorer: &Scorer,
+                   config: &Config) -> Option<isize> {
+        if !self.best_terms(line, query, scratch, scorer, config) {
+            return None;
+        }
+
+        let total: isize = scratch.best.iter().map(|&(_, score, _)| {score}).sum();
+
+        Some(total + self.length_score(line, config) + self.recency_score(config) + self.count_score(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::iter;
+
+    use super::*;
+    use config::Config;
+
+    fn base(lines: &[&str]) -> SearchBase {
+        let mut base = SearchBase::new(Config::default());
+        for line in lines.iter() {
+            base.add_line(line.to_string(), None);
+        }
+        base
+    }
+
+    // the lines matching query and the typos each needed
+    fn search(base: &SearchBase, query: &str) -> Vec<(String, isize)> {
+        let result = base.query(query, SearchMode::Fuzzy, true, 10, 0, &mut Scratch::default());
+        result.matches.iter().map(|item| {
+            let typos = item.explanation().unwrap().parts.iter().filter(|&&(ref name, _)| {
+                name.ends_with(":typos")
+            }).map(|&(_, value)| {-value / base.config.typo_penalty}).sum();
+            (item.line().to_string(), typos)
+        }).collect()
+    }
+
+    #[test]
+    fn typos() {
+        let base = base(&["git status", "ls -la", "cargo build"]);
+        // swapped, and an extra character
+        assert_eq!(search(&base, "gtis"), vec![("git status".to_owned(), 1)]);
+        assert_eq!(search(&base, "gixt"), vec![("git status".to_owned(), 1)]);
+        // a typo in each term
+        assert_eq!(search(&base, "cagro bulid"), vec![("cargo build".to_owned(), 2)]);
+        // four characters allow one typo
+        assert_eq!(search(&base, "gxyt"), vec![]);
+        // no typos when something matches
+        assert_eq!(search(&base, "gst"), vec![("git status".to_owned(), 0)]);
+    }
+
+    #[test]
+    fn typos_on_long_lines() {
+        // a query allowing six typos against a long line, with every
+        // character of the query in it many times
+        let line: String = iter::repeat("abcdefghijkl ").take(200).collect();
+        let base = base(&[&line]);
+        assert_eq!(search(&base, "bacdfeghjikllkjihgfedcba").len(), 1);
+    }
+
+    fn subsequence(query: &[char], line: &[char]) -> bool {
+        let mut rest = line.iter();
+        query.iter().all(|c| {rest.any(|l| {l == c})})
+    }
+
+    // the fewest drops and swaps of next characters, made one after another,
+    // that turn query into a subsequence of line
+    fn fewest_edits(query: &str, line: &str, typos: usize) -> Option<usize> {
+        let line: Vec<char> = line.chars().collect();
+        let mut seen: HashSet<Vec<char>> = HashSet::new();
+        let mut current = vec![query.chars().collect::<Vec<char>>()];
+        for edits in 0..typos + 1 {
+            if current.iter().any(|q| {!q.is_empty() && subsequence(q, &line)}) {
+                return Some(edits);
+            }
+            let mut next = vec![];
+            for q in current.iter() {
+                for i in 0..q.len() {
+                    let mut dropped = q.clone();
+                    dropped.remove(i);
+                    let mut swapped = q.clone();
+                    if i + 1 < q.len() {
+                        swapped.swap(i, i + 1);
                     }
-                    // sum the heatmap
-                    let heat_sum: isize = pgroup.iter().map(|pos| {self.heatmap[*pos]}).sum();
-                    let score = (dist_total / dist_count) * DIST_WEIGHT +
-                        heat_sum * HEAT_WEIGHT;
-                    match top_score {
-                        None => top_score = Some(score),
-                        Some(last) => {
-                            if score > last {
-                                top_score = Some(score);
-                            }
+                    for variant in vec![dropped, swapped].into_iter() {
+                        if seen.insert(variant.clone()) {
+                            next.push(variant);
                         }
                     }
                 }
+            }
+            current = next;
+        }
+        None
+    }
 
-                // return the result
-                match top_score {
-                    None => None,
-                    Some(score) => {
-                        Some(score + self.factor / FACTOR_REDUCE)
-                    }
-                }
+    #[test]
+    fn typos_match_every_edit() {
+        let mut seed: u32 = 3;
+        let mut next = |limit: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % limit
+        };
+        let letters = ['a', 'b', 'c', 'd'];
+
+        for _ in 0..500 {
+            let line: String = (0..1 + next(10)).map(|_| {letters[next(4)]}).collect();
+            let query: String = (0..4 + next(6)).map(|_| {letters[next(4)]}).collect();
+            let base = base(&[&line]);
+
+            let typos = query.len() / base.config.typo_every;
+            let expected = fewest_edits(&query, &line, typos).map(|edits| {(line.clone(), edits as isize)});
+            assert_eq!(search(&base, &query), expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    // the lines of a page of matches, and how many matched in all
+    fn page(base: &SearchBase, query: &str, mode: SearchMode, k: usize, offset: usize) -> (Vec<String>, usize) {
+        let result = base.query(query, mode, false, k, offset, &mut Scratch::default());
+        assert_eq!(result.offset, offset);
+        (result.matches.iter().map(|item| {item.line().to_string()}).collect(), result.total)
+    }
+
+    #[test]
+    fn pages() {
+        // the same length and heat, so only recency tells them apart
+        let lines: Vec<String> = (10..35).map(|n| {format!("git {}", n)}).collect();
+        let mut base = SearchBase::new(Config::default());
+        for line in lines.iter() {
+            base.add_line(line.clone(), None);
+        }
+        base.add_line("ls".to_owned(), None);
+
+        for &mode in [SearchMode::Fuzzy, SearchMode::Regex].iter() {
+            let (all, total) = page(&base, "git", mode, 100, 0);
+            assert_eq!(total, 25);
+            // the most recent first
+            let recent: Vec<String> = lines.iter().rev().cloned().collect();
+            assert_eq!(all, recent);
+
+            let mut pages = vec![];
+            for offset in [0, 10, 20].iter() {
+                let (lines, total) = page(&base, "git", mode, 10, *offset);
+                assert_eq!(total, 25);
+                pages.extend(lines.into_iter());
             }
+            assert_eq!(pages, all);
+
+            // past the end
+            assert_eq!(page(&base, "git", mode, 10, 30), (vec![], 25));
+
+            // nothing to allocate or overflow for huge pages
+            assert_eq!(page(&base, "git", mode, ::std::usize::MAX, 0), (all.clone(), 25));
+            assert_eq!(page(&base, "git", mode, ::std::usize::MAX, ::std::usize::MAX), (vec![], 25));
+        }
+    }
+
+    #[test]
+    fn shell_syntax_ranks_higher() {
+        // the line that should win comes first, so recency works against it
+        let ranked = |lines: &[&str], query: &str| {
+            page(&base(lines), query, SearchMode::Fuzzy, 10, 0).0
+        };
+        assert_eq!(ranked(&["git commit", "grep config"], "gc"),
+                   vec!["git commit".to_owned(), "grep config".to_owned()]);
+        assert_eq!(ranked(&["kubectl --deploy", "kubectl redeploy"], "dep"),
+                   vec!["kubectl --deploy".to_owned(), "kubectl redeploy".to_owned()]);
+        assert_eq!(ranked(&["cat log | grep x", "cat log x grep"], "gr"),
+                   vec!["cat log | grep x".to_owned(), "cat log x grep".to_owned()]);
+        assert_eq!(ranked(&["make && test", "make .. test"], "te"),
+                   vec!["make && test".to_owned(), "make .. test".to_owned()]);
+        assert_eq!(ranked(&["echo $(date)", "echo #(date)"], "da"),
+                   vec!["echo $(date)".to_owned(), "echo #(date)".to_owned()]);
+        assert_eq!(ranked(&["run --env=prod", "run --env:prod"], "prod"),
+                   vec!["run --env=prod".to_owned(), "run --env:prod".to_owned()]);
+    }
+
+    #[test]
+    fn identifier_words_rank_higher() {
+        for &(line, positions) in [("getUserName", [0, 3, 7]), ("get_user_name", [0, 4, 9])].iter() {
+            // the same letters mid-word, and more recent
+            let base = base(&[line, "getusername"]);
+            let result = base.query("gun", SearchMode::Fuzzy, false, 10, 0, &mut Scratch::default());
+            assert_eq!(result.matches.len(), 2);
+            assert_eq!(result.matches[0].line(), line);
+            assert_eq!(result.matches[0].positions(), &[positions.to_vec()][..]);
+        }
+    }
+
+    #[test]
+    fn zero_weights_leave_the_explanation() {
+        let explain = |config: Config| {
+            let mut base = SearchBase::new(config);
+            base.add_line("xx git commit".to_owned(), None);
+            let result = base.query("gco", SearchMode::Fuzzy, true, 10, 0, &mut Scratch::default());
+            result.matches[0].explanation().unwrap().clone()
+        };
+        let part = |explanation: &Explanation, name: &str| {
+            explanation.parts.iter().find(|&&(ref n, _)| {n == name}).unwrap().1
+        };
+
+        let default = explain(Config::default());
+        let mut config = Config::default();
+        config.run_weight = 0;
+        config.lead_weight = 0;
+        let zeroed = explain(config);
+        assert!(part(&default, "gco:run") != 0);
+        assert!(part(&default, "gco:lead") != 0);
+        assert_eq!(part(&zeroed, "gco:run"), 0);
+        assert_eq!(part(&zeroed, "gco:lead"), 0);
+        assert_eq!(zeroed.total, default.total - part(&default, "gco:run") - part(&default, "gco:lead"));
+        assert_eq!(zeroed.parts.iter().map(|&(_, value)| {value}).sum::<isize>(), zeroed.total);
+    }
+
+    #[test]
+    fn regex_ranks_like_fuzzy() {
+        let path = env::temp_dir().join("bis-test-regex-picks");
+        let log = format!("{}\tmake\tmake check\n", ::frecency::now());
+        File::create(&path).and_then(|mut f| {f.write_all(log.as_bytes())}).unwrap();
+
+        let mut config = Config::default();
+        config.selections = Some(path.clone());
+        let mut base = SearchBase::new(config);
+        for line in ["make test", "make test", "make test", "make test", "make check", "make build"].iter() {
+            base.add_line(line.to_string(), None);
         }
+        base.read_selections();
+        fs::remove_file(&path).unwrap();
+
+        // picked, then run most often, then most recent
+        let ranked = vec!["make check".to_owned(), "make test".to_owned(), "make build".to_owned()];
+        assert_eq!(page(&base, "make", SearchMode::Fuzzy, 10, 0), (ranked.clone(), 3));
+        assert_eq!(page(&base, "make", SearchMode::Regex, 10, 0), (ranked, 3));
+    }
+
+    #[test]
+    fn explanations_have_the_positions() {
+        let base = base(&["git commit -m cafe\u{301}", "ls"]);
+        let result = base.query("gco !ls cafe", SearchMode::Fuzzy, true, 10, 0, &mut Scratch::default());
+        let explanation = result.matches[0].explanation().unwrap();
+        // excluded terms have none, and a combining mark is part of its letter
+        assert_eq!(explanation.positions, vec![vec![0, 4, 5], vec![14, 15, 16, 17]]);
+        let highlighted: Vec<Vec<usize>> = result.matches[0].positions().iter().filter(|group| {
+            !group.is_empty()
+        }).cloned().collect();
+        assert_eq!(explanation.positions, highlighted);
+    }
+
+    #[test]
+    fn respelling_keeps_one_copy() {
+        let mut respelled = base(&["git  status", "ls", "git status;", "git status", "make"]);
+        let mut fresh = base(&["ls", "git status", "make"]);
+        respelled.shrink_to_fit();
+        fresh.shrink_to_fit();
+        assert_eq!(respelled.memory(), fresh.memory());
+
+        let result = respelled.query("gst", SearchMode::Fuzzy, false, 10, 0, &mut Scratch::default());
+        assert_eq!(result.matches.len(), 1);
+        assert_eq!(result.matches[0].line(), "git status");
+        assert_eq!(result.matches[0].positions(), &[vec![0, 4, 5]][..]);
+    }
+
+    #[test]
+    fn running_a_line_again_makes_it_recent() {
+        let mut base = SearchBase::new(Config::default());
+        for line in ["git 1", "git 2", "git 3", "git 1"].iter() {
+            base.add_line(line.to_string(), None);
+        }
+        assert_eq!(page(&base, "git", SearchMode::Fuzzy, 10, 0),
+                   (vec!["git 1".to_owned(), "git 3".to_owned(), "git 2".to_owned()], 3));
+    }
+
+    #[test]
+    fn picks_count_for_every_spelling() {
+        let path = env::temp_dir().join("bis-test-picks");
+        let log = format!("{}\tmake\tmake test;\n", ::frecency::now());
+        File::create(&path).and_then(|mut f| {f.write_all(log.as_bytes())}).unwrap();
+
+        let mut config = Config::default();
+        config.selections = Some(path.clone());
+        let mut base = SearchBase::new(config);
+        base.add_line("make  test".to_owned(), None);
+        base.add_line("make build".to_owned(), None);
+        base.read_selections();
+        // respelled after the selections were read
+        base.add_line("make test".to_owned(), None);
+        // new after the selections were read
+        base.add_line("make test;;".to_owned(), None);
+        base.add_line("make check".to_owned(), None);
+        fs::remove_file(&path).unwrap();
+
+        let result = base.query("make", SearchMode::Fuzzy, true, 10, 0, &mut Scratch::default());
+        assert_eq!(result.matches[0].line(), "make test;;");
+        let frecency = result.matches[0].explanation().unwrap().parts.iter()
+            .find(|&&(ref name, _)| {name == "frecency"}).map(|&(_, score)| {score});
+        assert!(frecency.unwrap() > 0);
     }
 }
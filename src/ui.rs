@@ -17,19 +17,28 @@ use term::terminfo::TermInfo;
 use unicode_width::*;
 
 use std::sync::mpsc::{Receiver, Sender};
-use std::borrow::{Cow, Borrow};
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::iter::FromIterator;
+use std::any::Any;
+use std::cell::Cell;
+use std::cmp;
+use std::panic::AssertUnwindSafe;
 
 use std::sync::mpsc;
 use std::io;
-use std::env;
 use std::thread;
+use std::panic;
 
 use bis_c::{TermTrack, TermSize};
-use error::StringError;
-use search::SearchBase;
+use error::{BisError, ErrorKind};
+use search::{SearchBase, SearchMode, QueryResult, Occurrences, Scratch};
+use config::Config;
 use constants::*;
+use history::Shell;
+
+// set on threads started by supervise, which catch their own panics
+thread_local!(static SUPERVISED: Cell<bool> = Cell::new(false));
 
 // TermControl contains utility funcitons for terminfo
 #[derive(Debug)]
@@ -50,24 +59,32 @@ enum TermStack {
 
 // our user interface instance
 pub struct UI {
-    // track is a guard, we never touch it manually
-    #[allow(dead_code)]
+    // track is a guard, we only touch it to suspend
     track: TermTrack,
     size: TermSize,
+    config: Config,
     control: TermControl,
-    query: Sender<String>,
-    matches: Receiver<Vec<Cow<'static, str>>>,
+    pending: String,
+    mode: SearchMode,
+    // show why each match scored what it did
+    explain: bool,
+    // the first match shown, how many fit and how many there are
+    offset: usize,
+    page: usize,
+    total: usize,
+    query: Sender<(String, SearchMode, bool, usize)>,
+    matches: Receiver<QueryResult>,
     chars: Receiver<char>,
     chars_stop: Sender<()>,
-    stop: Receiver<()>
+    errors: Receiver<BisError>
 }
 
 impl TermControl {
-    pub fn create() -> Result<TermControl, StringError> {
+    pub fn create() -> Result<TermControl, BisError> {
         debug!("Getting terminal info");
         let info = match TermInfo::from_env() {
             Ok(info) => info,
-            Err(e) => return Err(StringError::new("Failed to get TermInfo", Some(Box::new(e))))
+            Err(e) => return Err(BisError::new(ErrorKind::TermInfo, "Failed to get TermInfo", Some(Box::new(e))))
         };
 
         trace!("Got terminfo: {:?}", info);
@@ -77,7 +94,7 @@ impl TermControl {
         for (name, value) in info.strings.into_iter() {
             strings.insert(name, match String::from_utf8(value) {
                 Ok(s) => s,
-                Err(e) => return Err(StringError::new("Failed to convert value into an OsString", Some(Box::new(e))))
+                Err(e) => return Err(BisError::new(ErrorKind::TermInfo, "Failed to convert value into an OsString", Some(Box::new(e))))
             });
         }
 
@@ -175,7 +192,7 @@ impl TermControl {
 }
 
 impl UI {
-    pub fn create() -> Result<UI, StringError> {
+    pub fn create(mut config: Config, base: SearchBase) -> Result<UI, BisError> {
         debug!("Creating TermControl");
         let control = try!(TermControl::create());
 
@@ -185,29 +202,64 @@ impl UI {
 
         debug!("Getting terminal size");
         let size = match track.get_size() {
-            Err(e) => return Err(StringError::new("Failed to get terminal size", Some(Box::new(e)))),
+            Err(e) => return Err(BisError::wrap("Failed to get terminal size", e)),
             Ok(s) => {
                 trace!("Terminal size: {:?}", s);
                 s
             }
         };
 
+        // leave a row for the prompt
+        config.matches = fit_matches(config.matches, &size);
+        debug!("Showing up to {} matches", config.matches);
+
         debug!("Preparing terminal");
         match track.prepare() {
-            Err(e) => return Err(StringError::new("Failed to prepare terminal", Some(Box::new(e)))),
+            Err(e) => return Err(BisError::wrap("Failed to prepare terminal", e)),
             Ok(_) => {
                 trace!("Terminal prepared successfully");
             }
         }
 
-        debug!("Masking sigint on main thread");
+        // put the terminal back even if we panic
+        debug!("Installing panic hook");
+        let cleanup = format!("{}{}{}\n",
+                              control.strings.get("rc").cloned().unwrap_or(format!("")),
+                              control.strings.get("clr_eos").cloned().unwrap_or(format!("")),
+                              control.strings.get("cnorm").cloned().unwrap_or(format!("")));
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if SUPERVISED.with(|s| s.get()) {
+                // the UI gets this as an error and cleans up itself
+                default_hook(info);
+                return;
+            }
 
-        match ::bis_c::mask_sigint() {
-            Ok(_) => {
-                trace!("Set signal mask successfully");
-            },
-            Err(e) => return Err(StringError::new("Failed to mask signal", Some(Box::new(e))))
-        }
+            match ::bis_c::restore_terminal() {
+                Ok(_) => {},
+                Err(e) => {
+                    error!("Error restoring terminal: {}", e);
+                }
+            }
+
+            // don't lock stdout here, the panicking thread may hold it
+            let _ = io::stderr().write_all(cleanup.as_bytes());
+
+            default_hook(info);
+        }));
+
+        // keep anything typed before we took over the terminal
+        debug!("Reading typeahead");
+        let pending = match ::bis_c::read_pending() {
+            Err(e) => return Err(BisError::wrap("Failed to read pending input", e)),
+            Ok(p) => {
+                trace!("Pending input: {:?}", p);
+                p
+            }
+        };
+
+        // worker failures are reported here
+        let (errors_tx, errors_rx) = mpsc::channel();
 
         debug!("Starting search thread");
 
@@ -216,9 +268,10 @@ impl UI {
         let (matches_tx, matches_rx) = mpsc::channel();
 
         trace!("Starting thread");
-        thread::spawn(move || {
-            search_thread(query_rx, matches_tx);
-        });
+        let k = config.matches;
+        try!(supervise("search", errors_tx.clone(), move || {
+            search_thread(base, k, query_rx, matches_tx)
+        }));
 
         debug!("Starting input thread");
 
@@ -227,44 +280,49 @@ impl UI {
         let (chars_stop_tx, chars_stop_rx) = mpsc::channel();
 
         trace!("Starting thread");
-        thread::spawn(move || {
+        try!(supervise("input", errors_tx, move || {
             input_thread(chars_tx, chars_stop_rx);
-        });
-
-        debug!("Starting signal thread");
-
-        trace!("Creating thread primitives");
-        let (stop_tx, stop_rx) = mpsc::channel();
+            Ok(())
+        }));
 
-        trace!("Starting thread");
-        thread::spawn(move || {
-            signal_thread(stop_tx);
-        });
+        let mode = if config.regex {
+            SearchMode::Regex
+        } else {
+            SearchMode::Fuzzy
+        };
+        let explain = config.explain;
 
         debug!("Creating UI instance");
         let instance = UI {
             track: track,
             size: size,
+            config: config,
             control: control,
+            pending: pending,
+            mode: mode,
+            explain: explain,
+            offset: 0,
+            page: 0,
+            total: 0,
             query: query_tx,
             matches: matches_rx,
             chars: chars_rx,
             chars_stop: chars_stop_tx,
-            stop: stop_rx
+            errors: errors_rx
         };
         
         trace!("Instance creation successful");
         Ok(instance)
     }
 
-    fn insert_match(&self, best_match: String) -> Result<(), StringError> {
+    fn insert_match(&self, best_match: String) -> Result<(), BisError> {
         // send the stop signal to the input thread
         match self.chars_stop.send(()) {
             Ok(_) => {
                 trace!("Successfully sent stop to input thread");
             },
             Err(e) => {
-                return Err(StringError::new("Failed to send stop signal to input thread", Some(Box::new(e))));
+                return Err(BisError::new(ErrorKind::Internal, "Failed to send stop signal to input thread", Some(Box::new(e))));
             }
         }
 
@@ -274,7 +332,7 @@ impl UI {
                 trace!("Successfully simulated input");
             },
             Err(e) => {
-                return Err(StringError::new("Failed to simulate input to console", Some(Box::new(e))))
+                return Err(BisError::wrap("Failed to simulate input to console", e))
             }
         }
 
@@ -291,29 +349,59 @@ impl UI {
             }
         }
 
-        match ::bis_c::insert_input(best_match) {
+        // a newline typed at the prompt would run what comes before it. bash
+        // and zsh take the character after C-v as it is, fish inserts a
+        // newline for M-Enter
+        let newline = match ::history::detect_shell() {
+            Some(Shell::Fish) => "\x1b\n",
+            _ => "\x16\n"
+        };
+
+        match ::bis_c::insert_input(best_match.replace('\n', newline)) {
             Ok(_) => {
                 trace!("Successfully inserted best match");
                 Ok(())
             },
             Err(e) => {
-                Err(StringError::new("Failed to simulate input to console", Some(Box::new(e))))
+                Err(BisError::wrap("Failed to simulate input to console", e))
+            }
+        }
+    }
+
+    fn prompt(&self) -> String {
+        match self.mode {
+            SearchMode::Fuzzy => self.config.prompt.clone(),
+            SearchMode::Regex => format!("[regex] {}", self.config.prompt)
+        }
+    }
+
+    fn send_query(&self, query: &String) -> Result<(), BisError> {
+        debug!("Sending {} to search thread", query);
+        match self.query.send((query.clone(), self.mode, self.explain, self.offset)) {
+            Ok(_) => {
+                trace!("Send successful");
+                Ok(())
+            },
+            Err(e) => {
+                Err(BisError::new(ErrorKind::Internal, "Failed to send to search thread", Some(Box::new(e))))
             }
         }
     }
 
-    pub fn start(&mut self) -> Result<(), StringError> {
+    pub fn start(&mut self) -> Result<(), BisError> {
         // assume start on a new line
         // get handles for io
         let handle = io::stdout();
         let mut output = handle.lock();
 
-        let mut query = String::new();
+        // typeahead becomes the initial query
+        let mut query = typeahead_query(&self.pending);
+        self.pending.clear();
 
         // make space for our matches
-        match write!(output, "{}{}", String::from_iter(vec!['\n'; MATCH_NUMBER].into_iter()),
-                     self.control.get_string("cuu".to_owned(), vec![TermStack::Int(MATCH_NUMBER as isize)]).unwrap_or(format!(""))) {
-            Err(e) => return Err(StringError::new("Failed to create space", Some(Box::new(e)))),
+        match write!(output, "{}{}", String::from_iter(vec!['\n'; self.config.matches].into_iter()),
+                     self.control.get_string("cuu".to_owned(), vec![TermStack::Int(self.config.matches as isize)]).unwrap_or(format!(""))) {
+            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to create space", Some(Box::new(e)))),
             Ok(_) => {
                 trace!("Successfully created space on terminal");
             }
@@ -321,14 +409,19 @@ impl UI {
 
         // draw our prompt and save the cursor
         debug!("Drawing prompt");
-        match write!(output, "{}{}", PROMPT,
+        match write!(output, "{}{}{}", self.prompt(), query,
                      self.control.get_string("sc".to_owned(), vec![]).unwrap_or(format!(""))) {
-            Err(e) => return Err(StringError::new("Failed to draw prompt", Some(Box::new(e)))),
+            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw prompt", Some(Box::new(e)))),
             Ok(_) => {
                 trace!("Drew prompt successfully");
             }
         }
 
+        if !query.is_empty() {
+            // search for the typeahead right away
+            try!(self.send_query(&query));
+        }
+
         // flush the output
         debug!("Flushing output");
         match output.flush() {
@@ -336,14 +429,14 @@ impl UI {
                 trace!("Successfully flushed output");
             },
             Err(e) => {
-                return Err(StringError::new("Failed to flush output", Some(Box::new(e))));
+                return Err(BisError::new(ErrorKind::Terminal, "Failed to flush output", Some(Box::new(e))));
             }
         }
 
         // are you kidding me with this stupid macro bullshit
         let matches_chan = &self.matches;
         let chars_chan = &self.chars;
-        let stop_chan = &self.stop;
+        let errors_chan = &self.errors;
 
         let mut best_match = None;
         let mut stopped = false;
@@ -352,54 +445,116 @@ impl UI {
             // this macro is bad and the rust people should feel bad
             // on the other hand, multi-threaded UI! Yay!
             select! {
-                _ = stop_chan.recv() => {
-                    // any event on this channel means stop
-                    debug!("Event on stop thread, exiting");
-
-                    // set the stopped variable
-                    stopped = true;
-
-                    // exit
-                    break;
+                maybe_error = errors_chan.recv() => {
+                    match maybe_error {
+                        Ok(error) => {
+                            return show_error(&mut self.control, &mut output, error);
+                        },
+                        Err(_) => {
+                            // every worker is gone, the other channels will hang up too
+                            trace!("Error channel hung up");
+                        }
+                    }
                 },
                 maybe_matches = matches_chan.recv() => {
-                    let matches = match maybe_matches {
+                    let result = match maybe_matches {
                         Ok(m) => m,
-                        Err(e) => return Err(StringError::new("Query thread hung up", Some(Box::new(e))))
+                        Err(e) => {
+                            // the search thread only stops early if it failed, and
+                            // its sender goes before supervise reports why
+                            match errors_chan.recv() {
+                                Ok(error) => return show_error(&mut self.control, &mut output, error),
+                                Err(_) => return Err(BisError::new(ErrorKind::Internal, "Query thread hung up", Some(Box::new(e))))
+                            }
+                        }
                     };
-                    debug!("Got matches: {:?}", matches);
+                    debug!("Got matches: {:?}", result);
 
                     // update the best match if we have one
-                    match matches.first() {
+                    match result.matches.first() {
                         Some(m) => {
-                            best_match = Some(m.clone());
+                            best_match = Some(m.line().clone());
                         },
                         None => {
                             best_match = None;
                         }
                     }
 
+                    // the status takes the first row, and so does paging if not everything fits
+                    let mut rows = page_rows(self.config.matches, self.explain);
+                    if result.status.is_some() || result.offset > 0 || result.total > result.offset + rows {
+                        rows = page_rows(self.config.matches.saturating_sub(1), self.explain);
+                    }
+                    let shown = cmp::min(rows, result.matches.len());
+
+                    self.page = rows;
+                    self.total = result.total;
+
+                    // what to say in the status row
+                    let mut parts = vec![];
+                    match result.status {
+                        Some(ref status) => parts.push(status.clone()),
+                        None => {}
+                    }
+                    if result.offset > 0 || result.total > result.offset + shown {
+                        if shown > 0 {
+                            parts.push(format!("{}-{} of {}", result.offset + 1, result.offset + shown, result.total));
+                        } else {
+                            parts.push(format!("{} matches", result.total));
+                        }
+                    }
+
+                    if parts.is_empty() {
+                        trace!("No status to draw");
+                    } else {
+                        let mut owned = parts.join(", ");
+                        while UnicodeWidthStr::width(owned.as_str()) > self.size.cols {
+                            owned.pop();
+                        }
+                        match write!(output, "\n{}", owned) {
+                            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw status", Some(Box::new(e)))),
+                            Ok(_) => {
+                                trace!("Drew status successfully");
+                            }
+                        }
+                    }
+
+                    let bold = self.control.get_string("bold".to_owned(), vec![]).unwrap_or(format!(""));
+                    let dim = self.control.get_string("dim".to_owned(), vec![]).unwrap_or(format!(""));
+                    let sgr0 = self.control.get_string("sgr0".to_owned(), vec![]).unwrap_or(format!(""));
+                    let now = ::frecency::now();
+
                     // draw the matches
-                    for item in matches.into_iter() {
-                        if UnicodeWidthStr::width(item.as_ref()) > self.size.cols {
-                            let mut owned = item.into_owned();
-                            while UnicodeWidthStr::width(owned.as_str()) > self.size.cols {
-                                // truncate long lines
-                                owned.pop();
+                    for item in result.matches.iter().take(rows) {
+                        // how often and when, after the line if it fits
+                        let note = annotate(item.occurrences(), now);
+                        let note_width = UnicodeWidthStr::width(note.as_str());
+                        let (cols, note) = if note.is_empty() || note_width + 1 >= self.size.cols {
+                            (self.size.cols, format!(""))
+                        } else {
+                            (self.size.cols - note_width - 1, format!(" {}{}{}", dim, note, sgr0))
+                        };
+
+                        // draw the match after a newline, showing the matched part of long lines
+                        match write!(output, "\n{}{}", highlight(item.line(), item.positions(), cols, &bold, &sgr0), note) {
+                            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw match", Some(Box::new(e)))),
+                            Ok(_) => {
+                                trace!("Drew match successfully");
                             }
-                            // draw the truncated item
-                            match write!(output, "\n{}", owned) {
-                                Err(e) => return Err(StringError::new("Failed to draw match", Some(Box::new(e)))),
-                                Ok(_) => {
-                                    trace!("Drew match successfully");
-                                }
+                        }
+
+                        if self.explain {
+                            let mut explanation = match item.explanation() {
+                                Some(e) => format!("  {}", e),
+                                None => format!("  no explanation")
+                            };
+                            while UnicodeWidthStr::width(explanation.as_str()) > self.size.cols {
+                                explanation.pop();
                             }
-                        } else {
-                            // draw the match after a newline
-                            match write!(output, "\n{}", item) {
-                                Err(e) => return Err(StringError::new("Failed to draw match", Some(Box::new(e)))),
+                            match write!(output, "\n{}", explanation) {
+                                Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw explanation", Some(Box::new(e)))),
                                 Ok(_) => {
-                                    trace!("Drew match successfully");
+                                    trace!("Drew explanation successfully");
                                 }
                             }
                         }
@@ -407,7 +562,7 @@ impl UI {
 
                     // restore the cursor
                     match write!(output, "{}", self.control.get_string("rc".to_owned(), vec![]).unwrap_or(format!(""))) {
-                        Err(e) => return Err(StringError::new("Failed to restore cursor", Some(Box::new(e)))),
+                        Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to restore cursor", Some(Box::new(e)))),
                         Ok(_) => {
                             trace!("Restored cursor successfully");
                         }
@@ -426,13 +581,161 @@ impl UI {
 
                     if chr.is_control() {
                         match chr {
-                            EOT => {
+                            EOT | CTRL_C => {
                                 // stop
                                 stopped = true;
 
                                 // exit
                                 break;
                             },
+                            CTRL_Z => {
+                                // clear our lines, give the terminal back and stop
+                                match write!(output, "{}\n",
+                                             self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to clear screen", Some(Box::new(e)))),
+                                    Ok(_) => {
+                                        trace!("Cleared screen successfully");
+                                    }
+                                }
+
+                                match output.flush() {
+                                    Ok(_) => {
+                                        trace!("Successfully flushed output");
+                                    },
+                                    Err(e) => {
+                                        return Err(BisError::new(ErrorKind::Terminal, "Failed to flush output", Some(Box::new(e))));
+                                    }
+                                }
+
+                                match self.track.restore() {
+                                    Err(e) => return Err(BisError::wrap("Failed to restore terminal", e)),
+                                    Ok(_) => {
+                                        trace!("Terminal restored successfully");
+                                    }
+                                }
+
+                                try!(::bis_c::suspend());
+
+                                // we've been continued, take the terminal back
+                                debug!("Resuming");
+                                // the terminal may have been resized while we were stopped
+                                self.size = match self.track.get_size() {
+                                    Err(e) => return Err(BisError::wrap("Failed to get terminal size", e)),
+                                    Ok(s) => {
+                                        trace!("Terminal size: {:?}", s);
+                                        s
+                                    }
+                                };
+                                self.config.matches = fit_matches(self.config.matches, &self.size);
+
+                                match self.track.prepare() {
+                                    Err(e) => return Err(BisError::wrap("Failed to prepare terminal", e)),
+                                    Ok(_) => {
+                                        trace!("Terminal prepared successfully");
+                                    }
+                                }
+
+                                // redraw the prompt and query on fresh lines
+                                match write!(output, "{}{}{}{}{}", String::from_iter(vec!['\n'; self.config.matches].into_iter()),
+                                             self.control.get_string("cuu".to_owned(), vec![TermStack::Int(self.config.matches as isize)])
+                                             .unwrap_or(format!("")),
+                                             self.prompt(), query,
+                                             self.control.get_string("sc".to_owned(), vec![]).unwrap_or(format!(""))) {
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw prompt", Some(Box::new(e)))),
+                                    Ok(_) => {
+                                        trace!("Drew prompt successfully");
+                                    }
+                                }
+
+                                // redraw the matches
+                                if !query.is_empty() {
+                                    try!(self.send_query(&query));
+                                }
+                            },
+                            CTRL_R => {
+                                // switch between fuzzy and regex search
+                                self.mode = match self.mode {
+                                    SearchMode::Fuzzy => SearchMode::Regex,
+                                    SearchMode::Regex => SearchMode::Fuzzy
+                                };
+                                debug!("Switched to {:?} mode", self.mode);
+                                self.offset = 0;
+
+                                // redraw the prompt, clearing the old matches
+                                match write!(output, "\r{}{}{}{}",
+                                             self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!("")),
+                                             self.prompt(), query,
+                                             self.control.get_string("sc".to_owned(), vec![]).unwrap_or(format!(""))) {
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw prompt", Some(Box::new(e)))),
+                                    Ok(_) => {
+                                        trace!("Drew prompt successfully");
+                                    }
+                                }
+
+                                best_match = None;
+
+                                if !query.is_empty() {
+                                    try!(self.send_query(&query));
+                                }
+                            },
+                            CTRL_E => {
+                                // show or hide why matches scored what they did
+                                self.explain = !self.explain;
+                                debug!("Explanations {}", if self.explain {"on"} else {"off"});
+
+                                // clear the old matches
+                                match write!(output, "{}{}",
+                                             self.control.get_string("rc".to_owned(), vec![]).unwrap_or(format!("")),
+                                             self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to clear screen", Some(Box::new(e)))),
+                                    Ok(_) => {
+                                        trace!("Cleared screen successfully");
+                                    }
+                                }
+
+                                if !query.is_empty() {
+                                    try!(self.send_query(&query));
+                                }
+                            },
+                            CTRL_N | CTRL_P => {
+                                // the next or previous page of matches
+                                let offset = if chr == CTRL_N && !query.is_empty() && self.offset + self.page < self.total {
+                                    Some(self.offset + self.page)
+                                } else if chr == CTRL_P && self.offset > 0 {
+                                    Some(self.offset.saturating_sub(self.page))
+                                } else {
+                                    None
+                                };
+
+                                match offset {
+                                    Some(offset) => {
+                                        self.offset = offset;
+                                        debug!("Showing matches from {}", offset);
+
+                                        // clear the old matches
+                                        match write!(output, "{}{}",
+                                                     self.control.get_string("rc".to_owned(), vec![]).unwrap_or(format!("")),
+                                                     self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
+                                            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to clear screen", Some(Box::new(e)))),
+                                            Ok(_) => {
+                                                trace!("Cleared screen successfully");
+                                            }
+                                        }
+
+                                        try!(self.send_query(&query));
+                                    },
+                                    None => {
+                                        // no more pages that way
+                                        // \u{7} is BEL
+                                        match write!(output, "\u{7}") {
+                                            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to output bell character", Some(Box::new(e)))),
+                                            Ok(_) => {
+                                                trace!("Successfully outputted bel character");
+                                            }
+                                        }
+                                    }
+                                }
+                            },
                             CTRL_U => {
                                 // move query.len() left, clear to end of screen
                                 match write!(output, "{}{}",
@@ -440,7 +743,7 @@ impl UI {
                                                                      vec![TermStack::Int(query.len() as isize)])
                                              .unwrap_or(format!("")),
                                              self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
-                                    Err(e) => return Err(StringError::new("Failed to create space", Some(Box::new(e)))),
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to create space", Some(Box::new(e)))),
                                     Ok(_) => {
                                         trace!("Successfully created space on terminal");
                                     }
@@ -448,19 +751,24 @@ impl UI {
 
                                 // clear the query
                                 query.clear();
+                                self.offset = 0;
 
                                 // clear the best match
                                 best_match = None;
                             },
-                            '\n' => {
+                            '\r' | '\n' => {
                                 // exit
                                 break;
                             },
+                            CTRL_S | CTRL_Q => {
+                                // flow control doesn't mean anything here
+                                trace!("Ignoring flow control character");
+                            },
                             _ => {
                                 // unknown character
                                 // \u{7} is BEL
                                 match write!(output, "\u{7}") {
-                                    Err(e) => return Err(StringError::new("Failed to output bell character", Some(Box::new(e)))),
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to output bell character", Some(Box::new(e)))),
                                     Ok(_) => {
                                         trace!("Successfully outputted bel character");
                                     }
@@ -468,12 +776,12 @@ impl UI {
                             }
                         }
                     } else {
-                        if UnicodeWidthStr::width(query.as_str()) + UnicodeWidthStr::width(PROMPT) +
+                        if UnicodeWidthStr::width(query.as_str()) + UnicodeWidthStr::width(self.prompt().as_str()) +
                             UnicodeWidthChar::width(chr).unwrap_or(0) >= self.size.cols {
                                 // don't allow users to type past the end of one line
                                 // \u{7} is BEL
                                 match write!(output, "\u{7}") {
-                                    Err(e) => return Err(StringError::new("Failed to output bell character", Some(Box::new(e)))),
+                                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to output bell character", Some(Box::new(e)))),
                                     Ok(_) => {
                                         trace!("Successfully outputted bel character");
                                     }
@@ -481,27 +789,20 @@ impl UI {
                         } else {
                             // push the character onto the query string
                             query.push(chr);
+                            self.offset = 0;
 
                             // draw the character, save the cursor position, clear the screen after us
                             match write!(output, "{}{}{}", chr,
                                          self.control.get_string("sc".to_owned(), vec![]).unwrap_or(format!("")),
                                          self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
-                                Err(e) => return Err(StringError::new("Failed to output character", Some(Box::new(e)))),
+                                Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to output character", Some(Box::new(e)))),
                                 Ok(_) => {
                                     trace!("Outputted character successfully");
                                 }
                             }
 
                             // send the search thread our query
-                            debug!("Sending {} to search thread", &query);
-                            match self.query.send(query.clone()) {
-                                Ok(_) => {
-                                    trace!("Send successful");
-                                },
-                                Err(e) => {
-                                    return Err(StringError::new("Failed to send to search thread", Some(Box::new(e))));
-                                }
-                            }
+                            try!(self.send_query(&query));
                         }
                     }
                 }
@@ -513,7 +814,7 @@ impl UI {
                     trace!("Successfully flushed output");
                 },
                 Err(e) => {
-                    return Err(StringError::new("Failed to flush output", Some(Box::new(e))));
+                    return Err(BisError::new(ErrorKind::Terminal, "Failed to flush output", Some(Box::new(e))));
                 }
             }
         }
@@ -523,7 +824,7 @@ impl UI {
             Some(ref m) => {
                 // redraw the best match
                 match write!(output, " -> {}", m) {
-                    Err(e) => return Err(StringError::new("Failed to write best match", Some(Box::new(e)))),
+                    Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to write best match", Some(Box::new(e)))),
                     Ok(_) => {
                         trace!("Drew best match successfully");
                     }
@@ -537,7 +838,7 @@ impl UI {
         // clear the screen and move to a new line
         match write!(output, "{}\n", 
                      self.control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!(""))) {
-            Err(e) => return Err(StringError::new("Failed to clear screen", Some(Box::new(e)))),
+            Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to clear screen", Some(Box::new(e)))),
             Ok(_) => {
                 trace!("Cleared screen successfully");
             }
@@ -549,13 +850,23 @@ impl UI {
                 trace!("Successfully flushed output");
             },
             Err(e) => {
-                return Err(StringError::new("Failed to flush output", Some(Box::new(e))));
+                return Err(BisError::new(ErrorKind::Terminal, "Failed to flush output", Some(Box::new(e))));
             }
         }
 
         if !stopped {
             match best_match {
                 Some(m) => {
+                    // not being able to remember the pick shouldn't lose it
+                    match ::frecency::record(&self.config, &m, &query) {
+                        Ok(_) => {
+                            trace!("Recorded selection successfully");
+                        },
+                        Err(e) => {
+                            warn!("Failed to record selection: {}", e);
+                        }
+                    }
+
                     try!(self.insert_match(m.into_owned()));
                 },
                 None => {
@@ -570,35 +881,268 @@ impl UI {
     }
 }
 
-// this thread waits for queries, and responds with search matches
-pub fn search_thread(query: Receiver<String>,
-                     matches: Sender<Vec<Cow<'static, str>>>) {
-    debug!("Starting query thread");
+// a short note of how often and how long ago a line was run
+fn annotate(occurrences: &Occurrences, now: u64) -> String {
+    let mut parts = vec![];
+
+    if occurrences.count > 1 {
+        parts.push(format!("{}\u{d7}", occurrences.count));
+    }
 
-    debug!("Getting history path");
-    let history_path = match env::var("HISTFILE") {
-        Ok(p) => {
-            trace!("Got history path: {:?}", p);
-            p
+    match occurrences.last_time {
+        Some(time) => {
+            let age = now.saturating_sub(time);
+            parts.push(if age < 60 {
+                format!("just now")
+            } else if age < 3600 {
+                format!("{}m ago", age / 60)
+            } else if age < 86400 {
+                format!("{}h ago", age / 3600)
+            } else if age < 86400 * 365 {
+                format!("{}d ago", age / 86400)
+            } else {
+                format!("{}y ago", age / (86400 * 365))
+            });
         },
-        Err(e) => panic!("Failed to get bash history file: {}", e)
+        None => {}
+    }
+
+    parts.join(", ")
+}
+
+// typeahead without the keys that aren't text. arrow and function keys
+// send escape sequences, which go whole, not just their escape
+fn typeahead_query(pending: &str) -> String {
+    let mut query = String::with_capacity(pending.len());
+    let mut chars = pending.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' {
+            if !c.is_control() {
+                query.push(c);
+            }
+            continue;
+        }
+
+        match chars.next() {
+            Some('[') => {
+                // parameters and intermediates up to a final byte
+                for c in chars.by_ref() {
+                    if c >= '\u{40}' && c <= '\u{7e}' {
+                        break;
+                    }
+                }
+            },
+            Some('O') => {
+                // one more for the keypad and F1 to F4
+                chars.next();
+            },
+            _ => {
+                // alt and a key
+            }
+        }
+    }
+
+    query
+}
+
+// the chars to show of a line wider than cols, with room for an ellipsis
+// at either end cut off. if the matches don't fit at the start, the
+// window is centred on them
+fn visible_window(widths: &[usize], positions: &[Vec<usize>], cols: usize) -> (usize, usize) {
+    // how far from start fits in room
+    let fit = |start: usize, room: usize| -> usize {
+        let mut used = 0;
+        let mut end = start;
+        while end < widths.len() && used + widths[end] <= room {
+            used += widths[end];
+            end += 1;
+        }
+        end
+    };
+
+    let end = fit(0, cols.saturating_sub(1));
+    let first = positions.iter().flat_map(|group| {group.iter()}).cloned().min();
+    let last = positions.iter().flat_map(|group| {group.iter()}).cloned().max();
+
+    match (first, last) {
+        (Some(first), Some(last)) if last >= end && last < widths.len() => {
+            let room = cols.saturating_sub(2);
+            let matched: usize = widths[first..last + 1].iter().sum();
+
+            // give half the spare room to what comes before the matches
+            let mut start = first;
+            if matched < room {
+                let mut spare = (room - matched) / 2;
+                while start > 0 && widths[start - 1] <= spare {
+                    start -= 1;
+                    spare -= widths[start];
+                }
+            }
+
+            let end = fit(start, room);
+            if end < widths.len() {
+                (start, end)
+            } else {
+                // no ellipsis at the end, so there's room for more at the start
+                let mut used: usize = widths[start..].iter().sum();
+                while start > 0 && used + widths[start - 1] <= cols.saturating_sub(1) {
+                    start -= 1;
+                    used += widths[start];
+                }
+                (start, end)
+            }
+        },
+        _ => (0, end)
+    }
+}
+
+// line with the matched positions wrapped in on and off, fit to cols wide
+fn highlight(line: &str, positions: &[Vec<usize>], cols: usize, on: &str, off: &str) -> String {
+    // a newline in a multi-line command would end the row
+    let chars: Vec<char> = line.chars().map(|c| {
+        if c == '\n' {
+            '\u{21b5}'
+        } else {
+            c
+        }
+    }).collect();
+    let widths: Vec<usize> = chars.iter().map(|c| {UnicodeWidthChar::width(*c).unwrap_or(0)}).collect();
+
+    let (start, end) = if widths.iter().sum::<usize>() <= cols {
+        (0, chars.len())
+    } else {
+        visible_window(&widths, positions, cols)
     };
 
-    let mut base = SearchBase::default();
-    
-    // read the history
-    info!("Reading history");
-    match base.read_history(history_path) {
+    let mut result = String::with_capacity(line.len());
+    let mut lit = false;
+
+    if start > 0 {
+        result.push('\u{2026}');
+    }
+
+    for idx in start..end {
+        let matched = positions.iter().any(|group| {group.contains(&idx)});
+        if matched && !lit {
+            result.push_str(on);
+            lit = true;
+        } else if !matched && lit {
+            result.push_str(off);
+            lit = false;
+        }
+
+        result.push(chars[idx]);
+    }
+
+    if lit {
+        result.push_str(off);
+    }
+
+    if end < chars.len() {
+        result.push('\u{2026}');
+    }
+
+    result
+}
+
+// draws a worker failure and turns it into our error
+fn show_error<T: Write>(control: &mut TermControl, output: &mut T, error: BisError) -> Result<(), BisError> {
+    error!("Worker failed: {}", error);
+
+    // draw the message under the prompt
+    match write!(output, "{}{}\n{}\n",
+                 control.get_string("rc".to_owned(), vec![]).unwrap_or(format!("")),
+                 control.get_string("clr_eos".to_owned(), vec![]).unwrap_or(format!("")),
+                 error) {
+        Err(e) => return Err(BisError::new(ErrorKind::Terminal, "Failed to draw error", Some(Box::new(e)))),
         Ok(_) => {
-            // success
+            trace!("Drew error successfully");
+        }
+    }
+
+    match output.flush() {
+        Ok(_) => {
+            trace!("Successfully flushed output");
         },
         Err(e) => {
-            panic!("Failed to read history: {}", e)
+            return Err(BisError::new(ErrorKind::Terminal, "Failed to flush output", Some(Box::new(e))));
         }
     }
 
+    Err(error)
+}
+
+// runs a worker thread, reporting any failure or panic on errors
+fn supervise<F>(name: &'static str, errors: Sender<BisError>, body: F) -> Result<(), BisError>
+    where F: FnOnce() -> Result<(), BisError> + Send + 'static {
+    let builder = thread::Builder::new().name(format!("{}", name));
+
+    match builder.spawn(move || {
+        SUPERVISED.with(|s| s.set(true));
+
+        let error = match panic::catch_unwind(AssertUnwindSafe(body)) {
+            Ok(Ok(())) => {
+                trace!("Thread {} finished successfully", name);
+                return;
+            },
+            Ok(Err(e)) => e,
+            Err(payload) => BisError::new(ErrorKind::Internal,
+                                          format!("The {} thread panicked: {}", name, panic_message(&payload)),
+                                          None)
+        };
+
+        match errors.send(error) {
+            Ok(_) => {
+                trace!("Sent error from {} thread", name);
+            },
+            Err(e) => {
+                // the UI is already gone
+                debug!("Failed to send error from {} thread: {:?}", name, e);
+            }
+        }
+    }) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(BisError::new(ErrorKind::Internal, format!("Failed to start {} thread", name), Some(Box::new(e))))
+    }
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    match payload.downcast_ref::<&'static str>() {
+        Some(s) => format!("{}", s),
+        None => match payload.downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None => format!("unknown cause")
+        }
+    }
+}
+
+// the most matches that fit under the prompt
+fn fit_matches(matches: usize, size: &TermSize) -> usize {
+    cmp::max(cmp::min(matches, size.rows.saturating_sub(1)), 1)
+}
+
+// how many matches fit in rows, explanations take a row under their match.
+// there's always room for one, or paging would stop moving
+fn page_rows(rows: usize, explain: bool) -> usize {
+    let matches = if explain {
+        rows / 2
+    } else {
+        rows
+    };
+    cmp::max(matches, 1)
+}
+
+// this thread waits for queries, and responds with search matches
+pub fn search_thread(base: SearchBase, k: usize, query: Receiver<(String, SearchMode, bool, usize)>,
+                     matches: Sender<QueryResult>) -> Result<(), BisError> {
+    debug!("Starting query thread");
+
     debug!("Starting query loop");
 
+    // reused by every query
+    let mut scratch = Scratch::default();
+
     loop {
         trace!("Waiting for a query");
         match query.recv() {
@@ -606,9 +1150,9 @@ pub fn search_thread(query: Receiver<String>,
                 debug!("Search thread exiting: {}", e);
                 break;
             },
-            Ok(q) => {
-                debug!("Got query: {:?}", q);
-                let result = base.query(q);
+            Ok((q, mode, explain, offset)) => {
+                debug!("Got query: {:?} ({:?}) from {}", q, mode, offset);
+                let result = base.query(q, mode, explain, k, offset, &mut scratch);
                 debug!("Got result: {:?}", result);
                 match matches.send(result) {
                     Err(e) => {
@@ -622,6 +1166,8 @@ pub fn search_thread(query: Receiver<String>,
             }
         }
     }
+
+    Ok(())
 }
 
 // this thread waits for input on stdin and sends that input back
@@ -666,37 +1212,61 @@ fn input_thread(chars: Sender<char>, stop: Receiver<()>) {
     debug!("Input thread ran out of input");
 }
 
-// this thread waits for interrupt signals so we can exit cleanly
-fn signal_thread(stop: Sender<()>) {
-    debug!("Starting signal thread");
+#[cfg(test)]
+mod tests {
+    use super::{typeahead_query, fit_matches, page_rows, visible_window};
+    use std::iter;
+    use bis_c::TermSize;
+
+    #[test]
+    fn typeahead_drops_escape_sequences() {
+        assert_eq!(typeahead_query("git st"), "git st");
+        // up, F1, F5, a shifted arrow and alt-b
+        assert_eq!(typeahead_query("a\u{1b}[Ab\u{1b}OPc\u{1b}[15~d\u{1b}[1;2Ce\u{1b}bf"), "abcdef");
+        assert_eq!(typeahead_query("ls\r\u{3}"), "ls");
+        assert_eq!(typeahead_query("caf\u{e9}\u{1b}"), "caf\u{e9}");
+    }
 
-    match ::bis_c::mask_sigint() {
-        Ok(_) => {
-            trace!("Set signal mask successfully");
-        },
-        Err(e) => {
-            panic!("Error setting signal mask: {:?}", e);
-        }
+    #[test]
+    fn matches_fit_under_the_prompt() {
+        assert_eq!(fit_matches(10, &TermSize {rows: 40, cols: 80}), 10);
+        assert_eq!(fit_matches(10, &TermSize {rows: 5, cols: 80}), 4);
+        assert_eq!(fit_matches(10, &TermSize {rows: 1, cols: 80}), 1);
+        assert_eq!(fit_matches(10, &TermSize {rows: 0, cols: 80}), 1);
     }
 
-    match ::bis_c::wait_sigint() {
-        Ok(_) => {
-            trace!("Waited for signal successfully");
-        },
-        Err(e) => {
-            panic!("Error waiting for signal: {:?}", e);
-        }
+    #[test]
+    fn pages_always_show_a_match() {
+        assert_eq!(page_rows(10, false), 10);
+        assert_eq!(page_rows(10, true), 5);
+        // the status row leaves one, or none at all
+        assert_eq!(page_rows(3 - 1, true), 1);
+        assert_eq!(page_rows(1 - 1, true), 1);
+        assert_eq!(page_rows(1 - 1, false), 1);
     }
 
-    match stop.send(()) {
-        Ok(_) => {
-            trace!("Sent stop signal successfully");
-        },
-        Err(e) => {
-            // this doesn't necessarily mean an error
-            debug!("Stop thread failed to send: {:?}", e);
-        }
+    #[test]
+    fn window_starts_at_the_beginning_when_matches_fit() {
+        let widths: Vec<usize> = iter::repeat(1).take(20).collect();
+        assert_eq!(visible_window(&widths, &[], 10), (0, 9));
+        assert_eq!(visible_window(&widths, &[vec![2, 3], vec![8]], 10), (0, 9));
     }
 
-    debug!("Thread got interrupt signal, exiting");
+    #[test]
+    fn window_centres_on_matches() {
+        let widths: Vec<usize> = iter::repeat(1).take(20).collect();
+        // ellipses at both ends leave 8 columns, 3 of the spare ones before the matches
+        assert_eq!(visible_window(&widths, &[vec![10, 11]], 10), (7, 15));
+        // nothing cut off at the end, so the window reaches back further
+        assert_eq!(visible_window(&widths, &[vec![15], vec![16]], 10), (11, 20));
+        // matches wider than the window keep the first one in view
+        assert_eq!(visible_window(&widths, &[vec![2, 18]], 10), (2, 10));
+    }
+
+    #[test]
+    fn window_counts_wide_characters() {
+        let widths: Vec<usize> = iter::repeat(2).take(10).collect();
+        assert_eq!(visible_window(&widths, &[], 10), (0, 4));
+        assert_eq!(visible_window(&widths, &[vec![9]], 10), (6, 10));
+    }
 }